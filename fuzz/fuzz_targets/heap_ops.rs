@@ -0,0 +1,137 @@
+//! Drive a `Heap` on a `SimBreak` with operations decoded from the fuzzer's bytes.
+//!
+//! ```notrust
+//! cargo +nightly fuzz run heap_ops
+//! ```
+//!
+//! Every live allocation is filled with its own sentinel byte which is checked
+//! before it is resized or freed, and the heap's invariants are checked after
+//! every operation. Sizes include zero and sizes near `usize::MAX`, alignments go
+//! up to 2^29, so the arithmetic in `split_block`, `absorb` and `extend_heap`
+//! gets pushed to its edges.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ralloc::{Heap, SimBreak};
+
+/// Room for a few large allocations, running out is part of the test.
+const HEAP_SIZE: usize = 64 << 20;
+
+struct Live {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+    byte: u8,
+}
+
+impl Live {
+    unsafe fn verify(&self) {
+        for i in 0..self.size {
+            assert_eq!(
+                *self.ptr.add(i),
+                self.byte,
+                "allocation {:?} of {} bytes was overwritten at {}",
+                self.ptr,
+                self.size,
+                i
+            );
+        }
+    }
+
+    fn overlaps(&self, ptr: *mut u8, size: usize) -> bool {
+        let (a, b) = (self.ptr as usize, ptr as usize);
+        a < b + size && b < a + self.size
+    }
+}
+
+/// The fuzzer's bytes, reading past the end gives zeros.
+struct Bytes<'a>(&'a [u8]);
+
+impl Bytes<'_> {
+    fn u8(&mut self) -> u8 {
+        match self.0.split_first() {
+            Some((b, rest)) => {
+                self.0 = rest;
+                *b
+            }
+            None => 0,
+        }
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    fn size(&mut self) -> usize {
+        match self.u8() % 8 {
+            0 => 0,
+            1 => usize::MAX - self.u8() as usize,
+            2 => self.u16() as usize * 64,
+            _ => self.u8() as usize,
+        }
+    }
+
+    fn align(&mut self) -> usize {
+        1 << (self.u8() % 30)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut heap = Heap::new(SimBreak::new(HEAP_SIZE));
+    let mut live: Vec<Live> = Vec::new();
+    let mut bytes = Bytes(data);
+    let mut byte = 0_u8;
+
+    unsafe {
+        while !bytes.0.is_empty() {
+            byte = byte.wrapping_add(1).max(1);
+            match bytes.u8() % 3 {
+                0 => {
+                    let (size, align) = (bytes.size(), bytes.align());
+                    let ptr = heap.malloc_aligned(size, align);
+                    if ptr.is_null() {
+                        continue;
+                    }
+                    assert_eq!(ptr as usize % align, 0, "{:?} is not aligned to {}", ptr, align);
+                    assert!(!live.iter().any(|l| l.overlaps(ptr, size)), "{:?} overlaps", ptr);
+                    ptr.write_bytes(byte, size);
+                    live.push(Live { ptr, size, align, byte });
+                }
+                1 if !live.is_empty() => {
+                    let i = bytes.u16() as usize % live.len();
+                    let size = bytes.size();
+                    live[i].verify();
+                    let ptr = heap.realloc(live[i].ptr, size, live[i].align);
+                    if ptr.is_null() {
+                        // The old allocation is untouched
+                        live[i].verify();
+                        continue;
+                    }
+                    let old = live.swap_remove(i);
+                    assert_eq!(ptr as usize % old.align, 0);
+                    assert!(!live.iter().any(|l| l.overlaps(ptr, size)), "{:?} overlaps", ptr);
+                    let kept = Live { ptr, size: old.size.min(size), ..old };
+                    kept.verify();
+                    ptr.write_bytes(old.byte, size);
+                    live.push(Live { ptr, size, ..old });
+                }
+                2 if !live.is_empty() => {
+                    let l = live.swap_remove(bytes.u16() as usize % live.len());
+                    l.verify();
+                    heap.free(l.ptr);
+                }
+                _ => {}
+            }
+            heap.check().unwrap();
+        }
+
+        for l in live.drain(..) {
+            l.verify();
+            heap.free(l.ptr);
+        }
+    }
+    heap.check().unwrap();
+    assert!(heap.base.is_null(), "the heap is not empty after freeing everything");
+    assert_eq!(heap.brk().used(), 0);
+});
@@ -0,0 +1,432 @@
+//! Where did this allocation come from 🧭
+//!
+//! With the `backtrace` feature every allocation records the stack it was made
+//! from by walking frame pointers, nothing is allocated and no symbols are looked
+//! up. Build with `RUSTFLAGS="-C force-frame-pointers=yes"` or the walk stops
+//! early. A frame pointer is only followed if it is aligned, above the last one
+//! and inside the thread's stack, which is looked up in `/proc/self/maps` the
+//! first time the thread captures a stack. The raw return addresses are resolved
+//! offline, `write_maps` gives you the `/proc/self/maps` needed to turn them into
+//! `addr2line` offsets.
+//!
+//! Stacks are stored once in a side table and each block points at its stack,
+//! when either table is full new allocations just go unrecorded. The block table
+//! is never filled past 7/8 so a lookup always finds an empty slot to stop at.
+//!
+//! Only x86_64 frame pointers are walked, on other targets stacks are empty.
+
+use core::mem;
+
+use crate::{block::Block, report::RawFd, syscall};
+
+/// Are allocation stacks captured.
+pub const ENABLED: bool = cfg!(feature = "backtrace");
+
+/// The most frames captured for one stack.
+pub const MAX_FRAMES: usize = 16;
+
+/// The number of different stacks that can be stored.
+pub const STACKS: usize = 4096;
+/// The number of slots for blocks with a stack.
+const BLOCKS: usize = 16384;
+/// The most blocks that can have a stack at once.
+const MAX_BLOCKS: usize = BLOCKS / 8 * 7;
+/// The number of slots in the index of stacks by hash, twice `STACKS` so there
+/// is always an empty one.
+const STACK_INDEX: usize = 2 * STACKS;
+
+static mut TABLE: SiteTable = SiteTable::EMPTY;
+
+/// The end of this thread's stack, zero until we have looked it up and
+/// `usize::MAX` if that failed.
+#[cfg(target_arch = "x86_64")]
+#[thread_local]
+static mut STACK_TOP: usize = 0;
+
+/// A captured call stack, the innermost frame first.
+#[derive(Clone, Copy, Debug)]
+pub struct Stack {
+    pub hash: u64,
+    len: usize,
+    frames: [usize; MAX_FRAMES],
+}
+
+impl Stack {
+    const EMPTY: Stack = Stack {
+        hash: 0,
+        len: 0,
+        frames: [0; MAX_FRAMES],
+    };
+
+    /// The return addresses of this stack, innermost first.
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.len]
+    }
+
+    /// Walk the frame pointers of the current thread.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let mut stack = Stack::EMPTY;
+        #[cfg(target_arch = "x86_64")]
+        stack.walk();
+        stack.hash = hash(stack.frames());
+        stack
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    fn walk(&mut self) {
+        let (mut fp, sp): (usize, usize);
+        unsafe { asm!("mov {}, rbp", "mov {}, rsp", out(reg) fp, out(reg) sp) };
+        let top = stack_top();
+
+        // The stack grows down so callers always have a higher frame pointer
+        let mut low = sp;
+        while self.len < MAX_FRAMES
+            && fp >= low
+            && fp % mem::align_of::<usize>() == 0
+            && fp.saturating_add(2 * mem::size_of::<usize>()) <= top
+        {
+            // [fp] is the caller's frame pointer, [fp + 8] the return address
+            let (next, ret) = unsafe { (*(fp as *const usize), *(fp as *const usize).add(1)) };
+            if ret == 0 {
+                break;
+            }
+            self.frames[self.len] = ret;
+            self.len += 1;
+            low = fp + 1;
+            fp = next;
+        }
+    }
+}
+
+/// The end of this thread's stack, zero if it can't be found.
+#[cfg(target_arch = "x86_64")]
+fn stack_top() -> usize {
+    unsafe {
+        if STACK_TOP == 0 {
+            let local = 0_u8;
+            STACK_TOP = mapping_end(&local as *const u8 as usize).unwrap_or(usize::MAX);
+        }
+        if STACK_TOP == usize::MAX {
+            0
+        } else {
+            STACK_TOP
+        }
+    }
+}
+
+/// The end of the mapping `addr` is in according to `/proc/self/maps`.
+#[cfg(target_arch = "x86_64")]
+fn mapping_end(addr: usize) -> Option<usize> {
+    unsafe {
+        let maps = syscall!(OPEN, b"/proc/self/maps\0".as_ptr(), 0);
+        if syscall::is_err(maps) {
+            return None;
+        }
+        // Every line starts with `start-end`, the rest of it is skipped
+        let (mut start, mut end, mut field) = (0_usize, 0_usize, 0);
+        let mut found = None;
+        let mut buf = [0_u8; 1024];
+        'read: loop {
+            let n = syscall!(READ, maps, buf.as_mut_ptr(), buf.len());
+            if syscall::is_err(n) || n == 0 {
+                break;
+            }
+            for b in buf[..n].iter() {
+                let digit = (*b as char).to_digit(16).map(|d| d as usize);
+                match (field, *b, digit) {
+                    (_, b'\n', _) => {
+                        if (start..end).contains(&addr) {
+                            found = Some(end);
+                            break 'read;
+                        }
+                        start = 0;
+                        end = 0;
+                        field = 0;
+                    }
+                    (0, b'-', _) => field = 1,
+                    (0, _, Some(d)) => start = start << 4 | d,
+                    (1, _, Some(d)) => end = end << 4 | d,
+                    _ => field = 2,
+                }
+            }
+        }
+        syscall!(CLOSE, maps);
+        found
+    }
+}
+
+/// FNV-1a over the return addresses.
+fn hash(frames: &[usize]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for f in frames {
+        for b in f.to_ne_bytes().iter() {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    h
+}
+
+/// A block and the index of the stack it was allocated from.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    block: usize,
+    stack: u32,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry { block: 0, stack: 0 };
+}
+
+/// Stacks and the blocks that were allocated from them.
+pub struct SiteTable {
+    stacks: [Stack; STACKS],
+    stacks_len: usize,
+    /// One more than the index of a stack in `stacks`, zero is empty. Open
+    /// addressing with linear probing keyed by the stack's hash.
+    index: [u32; STACK_INDEX],
+    /// Open addressing with linear probing keyed by block address.
+    blocks: [Entry; BLOCKS],
+    blocks_len: usize,
+}
+
+unsafe impl Send for SiteTable {}
+unsafe impl Sync for SiteTable {}
+
+impl SiteTable {
+    pub const EMPTY: SiteTable = SiteTable {
+        stacks: [Stack::EMPTY; STACKS],
+        stacks_len: 0,
+        index: [0; STACK_INDEX],
+        blocks: [Entry::EMPTY; BLOCKS],
+        blocks_len: 0,
+    };
+
+    /// Store `stack` if we have not seen it before, returns its index.
+    pub fn intern(&mut self, stack: &Stack) -> Option<u32> {
+        let mut i = stack.hash as usize % STACK_INDEX;
+        loop {
+            match self.index[i] {
+                0 => break,
+                idx => {
+                    let known = &self.stacks[idx as usize - 1];
+                    if known.hash == stack.hash && known.frames() == stack.frames() {
+                        return Some(idx - 1);
+                    }
+                }
+            }
+            i = (i + 1) % STACK_INDEX;
+        }
+        if self.stacks_len == STACKS {
+            return None;
+        }
+        self.stacks[self.stacks_len] = *stack;
+        self.stacks_len += 1;
+        self.index[i] = self.stacks_len as u32;
+        Some(self.stacks_len as u32 - 1)
+    }
+
+    fn slot(block: usize) -> usize {
+        // Blocks are word aligned, drop the bits that are always zero
+        (block / mem::align_of::<usize>()).wrapping_mul(0x9E37_79B9) % BLOCKS
+    }
+
+    /// Remember that `block` was allocated from the stack at `stack`, fails
+    /// when `MAX_BLOCKS` blocks already have one.
+    pub fn insert(&mut self, block: usize, stack: u32) -> Result<(), ()> {
+        let mut i = Self::slot(block);
+        loop {
+            match self.blocks[i].block {
+                b if b == block => break,
+                0 if self.blocks_len < MAX_BLOCKS => {
+                    self.blocks_len += 1;
+                    break;
+                }
+                0 => return Err(()),
+                _ => i = (i + 1) % BLOCKS,
+            }
+        }
+        self.blocks[i] = Entry { block, stack };
+        Ok(())
+    }
+
+    fn find(&self, block: usize) -> Option<usize> {
+        let mut i = Self::slot(block);
+        loop {
+            match self.blocks[i].block {
+                0 => return None,
+                b if b == block => return Some(i),
+                _ => i = (i + 1) % BLOCKS,
+            }
+        }
+    }
+
+    /// The index of the stack `block` was allocated from.
+    pub fn get(&self, block: usize) -> Option<u32> {
+        self.find(block).map(|i| self.blocks[i].stack)
+    }
+
+    /// The number of stacks stored.
+    pub fn len(&self) -> usize {
+        self.stacks_len
+    }
+
+    /// The stack stored at `idx`.
+    pub fn stack(&self, idx: u32) -> &Stack {
+        &self.stacks[idx as usize]
+    }
+
+    /// Remove `block` shifting back any entries that probed past it, returns
+    /// the index of its stack.
+    pub fn remove(&mut self, block: usize) -> Option<u32> {
+        let mut hole = self.find(block)?;
+        let stack = self.blocks[hole].stack;
+        let mut i = hole;
+        loop {
+            i = (i + 1) % BLOCKS;
+            let e = self.blocks[i];
+            if e.block == 0 {
+                break;
+            }
+            let home = Self::slot(e.block);
+            // Move `e` into the hole if its home is not between the hole and `i`
+            let between = if hole <= i {
+                hole < home && home <= i
+            } else {
+                hole < home || home <= i
+            };
+            if !between {
+                self.blocks[hole] = e;
+                hole = i;
+            }
+        }
+        self.blocks[hole] = Entry::EMPTY;
+        self.blocks_len -= 1;
+        Some(stack)
+    }
+}
+
+/// Remember the current stack as the allocation site of `blk`.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn record(blk: *mut Block) {
+    let stack = Stack::capture();
+    if let Some(idx) = TABLE.intern(&stack) {
+        let _ = TABLE.insert(blk as usize, idx);
+    }
+}
+
+/// `blk` was freed, forget where it came from.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn forget(blk: *mut Block) {
+    let _ = TABLE.remove(blk as usize);
+}
+
+/// The index of the stack `blk` was allocated from, see `stack`.
+pub fn site(blk: *const Block) -> Option<usize> {
+    unsafe { TABLE.get(blk as usize).map(|idx| idx as usize) }
+}
+
+/// The stack stored at `idx`.
+pub fn stack(idx: usize) -> &'static Stack {
+    unsafe { TABLE.stack(idx as u32) }
+}
+
+/// Copy `/proc/self/maps` to `fd` so return addresses can be resolved offline.
+pub fn write_maps(fd: usize) {
+    unsafe {
+        let maps = syscall!(OPEN, b"/proc/self/maps\0".as_ptr(), 0);
+        if syscall::is_err(maps) {
+            return;
+        }
+        let mut buf = [0_u8; 1024];
+        loop {
+            let n = syscall!(READ, maps, buf.as_mut_ptr(), buf.len());
+            if syscall::is_err(n) || n == 0 {
+                break;
+            }
+            let _ = RawFd(fd).write_bytes(&buf[..n]);
+        }
+        syscall!(CLOSE, maps);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn site_table() {
+        // Far too big for the stack, all zeros is `SiteTable::EMPTY`
+        let mut table = unsafe {
+            let layout = std::alloc::Layout::new::<SiteTable>();
+            Box::from_raw(std::alloc::alloc_zeroed(layout) as *mut SiteTable)
+        };
+        let stack = Stack::capture();
+        let idx = table.intern(&stack).unwrap();
+        assert_eq!(table.intern(&stack), Some(idx));
+
+        // Two blocks that want the same slot
+        let a = 8;
+        let b = (2..)
+            .map(|i| a * i)
+            .find(|b| SiteTable::slot(*b) == SiteTable::slot(a))
+            .unwrap();
+        table.insert(a, idx).unwrap();
+        table.insert(b, idx).unwrap();
+        assert_eq!(table.remove(a), Some(idx));
+        assert!(table.get(a).is_none());
+        assert_eq!(table.get(b), Some(idx));
+    }
+
+    #[test]
+    fn full_site_table() {
+        let mut table = unsafe {
+            let layout = std::alloc::Layout::new::<SiteTable>();
+            Box::from_raw(std::alloc::alloc_zeroed(layout) as *mut SiteTable)
+        };
+        let stack = Stack::capture();
+        let idx = table.intern(&stack).unwrap();
+        let blocks = (1..=BLOCKS).map(|i| i * 8);
+        let inserted = blocks.clone().filter(|b| table.insert(*b, idx).is_ok()).count();
+        assert_eq!(inserted, MAX_BLOCKS);
+
+        // Lookups and removes of blocks that aren't there stop at an empty slot
+        assert!(table.get(BLOCKS * 8).is_none());
+        assert!(table.remove(BLOCKS * 8).is_none());
+        assert_eq!(table.remove(8), Some(idx));
+        assert!(table.insert(BLOCKS * 8, idx).is_ok());
+        assert!(table.insert(BLOCKS * 8 + 8, idx).is_err());
+
+        // As many stacks as fit, each found again
+        let fake = |ret| {
+            let mut stack = Stack::EMPTY;
+            stack.frames[0] = ret;
+            stack.len = 1;
+            stack.hash = hash(stack.frames());
+            stack
+        };
+        for i in 1..STACKS {
+            assert_eq!(table.intern(&fake(i)), Some(i as u32));
+        }
+        assert_eq!(table.intern(&fake(STACKS)), None);
+        assert_eq!(table.intern(&fake(1)), Some(1));
+        assert_eq!(table.intern(&stack), Some(idx));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn thread_stack() {
+        let top = stack_top();
+        let here = &top as *const usize as usize;
+        assert!(here < top && top - here < 64 << 20);
+        let stack = std::thread::spawn(|| (stack_top(), Stack::capture())).join().unwrap();
+        assert_ne!(stack.0, top);
+        assert!(stack.1.frames().len() <= MAX_FRAMES);
+    }
+}
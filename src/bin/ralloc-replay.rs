@@ -0,0 +1,247 @@
+//! Replay a recorded allocation trace 🔁
+//!
+//! ```notrust
+//! ralloc-replay [--system] TRACE
+//! ```
+//!
+//! Record a trace by running a program built with the `trace` feature and
+//! `RALLOC_TRACE=TRACE` set. Every call in it is made again against ralloc, or
+//! against the system allocator with `--system`, mapping the recorded pointers
+//! to the ones we get back. The wall time, peak RSS, peak heap size,
+//! fragmentation and syscall counts are printed at the end.
+//!
+//! A trace is a 16 byte header then 48 byte events, everything in native byte
+//! order.
+//!
+//! ```notrust
+//! header  0  8  magic   "RALLOCTR"
+//!         8  4  version 1
+//!        12  4  size    the size of an event, 48
+//! event   0  8  time    nanoseconds, events are replayed in this order
+//!         8  4  tid     thread id, ignored
+//!        12  4  op      1 alloc, 2 alloc_zeroed, 3 realloc, 4 dealloc
+//!        16  8  ptr     the pointer returned, or freed by dealloc
+//!        24  8  old     the pointer passed to realloc, otherwise 0
+//!        32  8  size    the size asked for, the new size for realloc
+//!        40  8  align   the alignment asked for
+//! ```
+//!
+//! Calls that returned null when they were recorded are not made again. The
+//! replay itself runs on the system allocator, everything it needs is
+//! allocated before the first replayed call.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    convert::TryInto,
+    env, fs, mem, process,
+    time::{Duration, Instant},
+};
+
+use ralloc::Ralloc;
+
+const MAGIC: &[u8; 8] = b"RALLOCTR";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const EVENT_SIZE: usize = 48;
+
+const ALLOC: u32 = 1;
+const ALLOC_ZEROED: u32 = 2;
+const REALLOC: u32 = 3;
+const DEALLOC: u32 = 4;
+
+#[derive(Clone, Copy, Debug)]
+struct Event {
+    time: u64,
+    op: u32,
+    ptr: u64,
+    old: u64,
+    size: usize,
+    align: usize,
+}
+
+impl Event {
+    fn parse(b: &[u8]) -> Self {
+        let u64_at = |at: usize| u64::from_ne_bytes(b[at..at + 8].try_into().unwrap());
+        Event {
+            time: u64_at(0),
+            op: u32::from_ne_bytes(b[12..16].try_into().unwrap()),
+            ptr: u64_at(16),
+            old: u64_at(24),
+            size: u64_at(32) as usize,
+            align: u64_at(40) as usize,
+        }
+    }
+}
+
+fn parse(trace: &[u8]) -> Result<Vec<Event>, String> {
+    if trace.len() < HEADER_SIZE || &trace[..8] != MAGIC {
+        return Err("not a ralloc trace".into());
+    }
+    let version = u32::from_ne_bytes(trace[8..12].try_into().unwrap());
+    let size = u32::from_ne_bytes(trace[12..16].try_into().unwrap()) as usize;
+    if version != VERSION || size != EVENT_SIZE {
+        return Err(format!("unsupported trace version {} with {} byte events", version, size));
+    }
+    let mut events: Vec<_> = trace[HEADER_SIZE..]
+        .chunks_exact(EVENT_SIZE)
+        .map(Event::parse)
+        .collect();
+    // Threads write their events in chunks, put them back in one timeline
+    events.sort_by_key(|e| e.time);
+    Ok(events)
+}
+
+/// What happened during a replay.
+#[derive(Debug, Default)]
+struct Replay {
+    elapsed: Duration,
+    /// Calls that could not be made, a free of a pointer we never saw or a bad layout.
+    skipped: usize,
+    /// Allocations that returned null when recorded, they are not made again.
+    failed: usize,
+    peak_live: usize,
+    /// Allocations still live at the end of the trace.
+    leaked: usize,
+}
+
+/// Make every call in `events` against `a`.
+unsafe fn replay<A: GlobalAlloc>(a: &A, events: &[Event]) -> Replay {
+    let mut out = Replay::default();
+    let mut live: HashMap<u64, (*mut u8, Layout)> = HashMap::with_capacity(events.len());
+    let mut live_bytes = 0;
+
+    let start = Instant::now();
+    for e in events {
+        let layout = match Layout::from_size_align(e.size, e.align) {
+            Ok(layout) if layout.size() != 0 => layout,
+            _ => {
+                out.skipped += 1;
+                continue;
+            }
+        };
+        // A failed realloc left the old allocation live, nothing changed
+        if e.ptr == 0 && e.op != DEALLOC {
+            out.failed += 1;
+            continue;
+        }
+        match e.op {
+            ALLOC | ALLOC_ZEROED => {
+                let ptr = if e.op == ALLOC {
+                    a.alloc(layout)
+                } else {
+                    a.alloc_zeroed(layout)
+                };
+                if ptr.is_null() {
+                    out.skipped += 1;
+                    continue;
+                }
+                live.insert(e.ptr, (ptr, layout));
+                live_bytes += layout.size();
+            }
+            REALLOC => {
+                let old = live.get(&e.old).copied();
+                let ptr = match old {
+                    Some((old, old_layout)) => a.realloc(old, old_layout, e.size),
+                    None => a.alloc(layout),
+                };
+                if ptr.is_null() {
+                    // A failed realloc leaves the old allocation alone
+                    out.skipped += 1;
+                    continue;
+                }
+                if let Some((_, old_layout)) = old {
+                    live.remove(&e.old);
+                    live_bytes -= old_layout.size();
+                }
+                live.insert(e.ptr, (ptr, layout));
+                live_bytes += layout.size();
+            }
+            DEALLOC => match live.remove(&e.ptr) {
+                Some((ptr, layout)) => {
+                    a.dealloc(ptr, layout);
+                    live_bytes -= layout.size();
+                }
+                None => out.skipped += 1,
+            },
+            _ => out.skipped += 1,
+        }
+        out.peak_live = out.peak_live.max(live_bytes);
+    }
+    out.elapsed = start.elapsed();
+
+    out.leaked = live.len();
+    for (_, (ptr, layout)) in live {
+        a.dealloc(ptr, layout);
+    }
+    out
+}
+
+/// The most memory this process has had resident in KiB.
+fn peak_rss() -> i64 {
+    unsafe {
+        let mut usage: libc::rusage = mem::zeroed();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage.ru_maxrss
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: ralloc-replay [--system] TRACE");
+    process::exit(2)
+}
+
+fn main() {
+    let mut system = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--system" => system = true,
+            _ if arg.starts_with('-') || path.is_some() => usage(),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let events = fs::read(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|trace| parse(&trace))
+        .unwrap_or_else(|e| {
+            eprintln!("ralloc-replay: {}: {}", path, e);
+            process::exit(1)
+        });
+
+    // Read the counters before anything after the replay can move them
+    let (run, stats) = unsafe {
+        if system {
+            (replay(&System, &events), None)
+        } else {
+            let run = replay(&Ralloc, &events);
+            (run, Some(ralloc::stats()))
+        }
+    };
+
+    println!("allocator      {}", if system { "system" } else { "ralloc" });
+    println!(
+        "events         {} ({} skipped, {} failed when recorded)",
+        events.len(),
+        run.skipped,
+        run.failed
+    );
+    println!("wall time      {:.3} ms", run.elapsed.as_secs_f64() * 1000.0);
+    println!("peak rss       {} KiB", peak_rss());
+    println!("peak live      {} bytes", run.peak_live);
+    println!("leaked         {} allocations", run.leaked);
+    if let Some(stats) = stats {
+        let overhead = stats.peak_heap_size.saturating_sub(run.peak_live);
+        println!("peak heap      {} bytes", stats.peak_heap_size);
+        println!(
+            "fragmentation  {:.1}% of the peak heap was not live data",
+            overhead as f64 * 100.0 / stats.peak_heap_size.max(1) as f64
+        );
+        println!(
+            "syscalls       brk {}, mmap {}, munmap {}, mprotect {}, madvise {}",
+            stats.brk, stats.mmap, stats.munmap, stats.mprotect, stats.madvise
+        );
+    }
+}
@@ -5,8 +5,10 @@ use core::{
 };
 
 use crate::{
-    breaks::{brk, sbrk},
+    breaks::Break,
     pointer::Pointer,
+    random::{self, RANDOMIZE},
+    report,
     sc as syscall,
     util::{align, MIN_ALIGN},
 };
@@ -19,6 +21,50 @@ use crate::{
 /// ```
 pub const BLOCK_SIZE: usize = align(mem::size_of::<Block>()) as usize;
 
+/// How many fitting free blocks `pick_random` chooses between.
+const RANDOM_CANDIDATES: usize = 8;
+
+/// Are the `next` and `prev` links mangled before they are stored.
+pub const SAFE_LINKING: bool = cfg!(feature = "safe-linking");
+
+/// The per process secret every link is mangled with.
+static mut LINK_SECRET: usize = 0;
+
+/// Pick the secret used to mangle links, this must happen before the first
+/// `Block` is made.
+pub fn init_link_secret() {
+    unsafe {
+        if SAFE_LINKING && LINK_SECRET == 0 {
+            LINK_SECRET = random::secret() as usize;
+        }
+    }
+}
+
+/// Hide a link before it is stored at `at`.
+///
+/// The address it is stored at and a random secret are mixed in so an overflow
+/// can't write a usable pointer, this is its own inverse.
+#[inline]
+fn mangle(link: *mut Block, at: *const *mut Block) -> *mut Block {
+    if !SAFE_LINKING {
+        return link;
+    }
+    ((link as usize) ^ ((at as usize) >> 12) ^ unsafe { LINK_SECRET }) as *mut Block
+}
+
+/// Recover a link stored at `at`, if it was tampered with we abort.
+#[inline]
+fn demangle(stored: *mut Block, at: *const *mut Block) -> *mut Block {
+    let link = mangle(stored, at);
+    if SAFE_LINKING && link as usize % mem::align_of::<Block>() != 0 {
+        report::abort(format_args!(
+            "heap corruption detected: the link at {:?} is invalid {:?}",
+            at, link
+        ));
+    }
+    link
+}
+
 /// The state of the blocks data.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum BlockState {
@@ -26,44 +72,61 @@ pub enum BlockState {
     InUse,
     /// The chunk of memory has been deallocated.
     Free,
+    /// The chunk of memory has been deallocated but must not be reused yet.
+    Quarantined,
 }
 
 /// ,___,<br>
 /// {O,o}<br>
 /// |)``)<br>
 /// HOOTIE!!<br>
-// TODO make accessors or do it right and make a proper Pointer type
-// to wrap *mut/const still need accessors though.
+// TODO make a proper Pointer type to wrap *mut/const.
 #[derive(Copy, Clone)]
 pub struct Block {
     pub size: usize,
     pub free: BlockState,
+    /// The `with_tag` tag this was allocated under.
+    pub tag: u8,
     pub data: *mut Block,
-    pub next: *mut Block,
-    pub prev: *mut Block,
+    /// The `check_leaks` scope this was allocated in, zero outside of one.
+    #[cfg(feature = "leak-report")]
+    scope: usize,
+    /// The size asked for, `size` is rounded up and has room for the redzone.
+    #[cfg(feature = "leak-report")]
+    requested: usize,
+    /// Use `next()` and `set_next()` this may be mangled.
+    next: *mut Block,
+    /// Use `prev()` and `set_prev()` this may be mangled.
+    prev: *mut Block,
 }
 
 impl fmt::Debug for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The links of a copy can't be demangled, it lives somewhere else
+        let copy = self as *const Block != self.data;
         f.debug_struct("Block")
             .field("size", &self.size)
             .field("free", &self.free)
+            .field("tag", &self.tag)
             .field("data", &self.data)
+            .field("scope", &self.scope())
             .field(
                 "next",
-                if self.next.is_null() {
+                if copy {
+                    &self.next
+                } else if self.next().is_null() {
                     &"null"
                 } else {
-                    unsafe { &(*self.next) }
+                    unsafe { &(*self.next()) }
                 },
             )
             .field(
                 "prev",
-                if self.prev.is_null() {
+                if !copy && self.prev().is_null() {
                     &"null"
                 } else {
                     &self.prev
-                    // unsafe { &(*self.prev) }
+                    // unsafe { &(*self.prev()) }
                 },
             )
             .finish()
@@ -75,65 +138,184 @@ impl Block {
         self.data
     }
 
+    /// The `check_leaks` scope this was allocated in.
+    #[cfg(feature = "leak-report")]
+    #[inline]
+    pub fn scope(&self) -> usize {
+        self.scope
+    }
+
+    #[cfg(feature = "leak-report")]
+    #[inline]
+    pub fn set_scope(&mut self, scope: usize) {
+        self.scope = scope;
+    }
+
+    /// The size the block was asked for.
+    #[cfg(feature = "leak-report")]
+    #[inline]
+    pub fn requested(&self) -> usize {
+        self.requested
+    }
+
+    #[cfg(feature = "leak-report")]
+    #[inline]
+    pub fn set_requested(&mut self, size: usize) {
+        self.requested = size;
+    }
+
+    /// Blocks only have a scope with the `leak-report` feature.
+    #[cfg(not(feature = "leak-report"))]
+    #[inline]
+    pub fn scope(&self) -> usize {
+        0
+    }
+
+    #[cfg(not(feature = "leak-report"))]
+    #[inline]
+    pub fn set_scope(&mut self, _scope: usize) {}
+
+    /// Blocks only remember the size asked for with the `leak-report` feature.
+    #[cfg(not(feature = "leak-report"))]
+    #[inline]
+    pub fn set_requested(&mut self, _size: usize) {}
+
     pub fn mark_free(&self) {
         unsafe { (*self.data).free = BlockState::Free };
     }
 
-    pub fn set_next(&self) {
-        unsafe { (*self.data).free = BlockState::Free };
+    /// The block after this one or null if this is the end of the heap.
+    pub fn next(&self) -> *mut Block {
+        demangle(self.next, &self.next)
     }
+
+    /// The block before this one or null if this is the start of the heap.
+    pub fn prev(&self) -> *mut Block {
+        demangle(self.prev, &self.prev)
+    }
+
+    pub fn set_next(&mut self, next: *mut Block) {
+        self.next = mangle(next, &self.next);
+    }
+
+    pub fn set_prev(&mut self, prev: *mut Block) {
+        self.prev = mangle(prev, &self.prev);
+    }
+
     ///
     /// # Safety
     /// It ain't
     pub unsafe fn from_raw(ptr: *mut u8, size: usize, prev: *mut Block) -> Self {
-        let mut blk = Self {
+        let data = ptr as *mut Block;
+        let blk = Self {
             size,
-            data: ptr as *mut Block,
+            data,
             free: BlockState::InUse,
+            tag: 0,
+            #[cfg(feature = "leak-report")]
+            scope: 0,
+            #[cfg(feature = "leak-report")]
+            requested: 0,
             next: ptr::null_mut(),
-            prev,
+            prev: ptr::null_mut(),
         };
-        ptr::write(ptr as *mut _, blk);
-        blk
+        ptr::write(data, blk);
+        // Links are mangled with where they are stored so set them in place
+        (*data).set_next(ptr::null_mut());
+        (*data).set_prev(prev);
+        *data
     }
 
-    pub fn find_block(mut last: *mut Block, size: usize) -> *mut Block {
+    /// The first free block starting at `base` with room for `size` bytes, or
+    /// the last block if none fit.
+    pub fn find_block(base: *mut Block, size: usize) -> *mut Block {
         unsafe {
-            let mut b = crate::GLOBAL_BASE;
+            if RANDOMIZE {
+                let b = Block::pick_random(base, size);
+                if !b.is_null() {
+                    crate::poison::verify(b, "malloc");
+                    return b;
+                }
+            }
+
+            let mut b = base;
             // println!("GB ptr {:?}", b);
             // It's gotta be Some and we keep looping if InUse && our blk is to small
             while !(b.is_null() || (*b).free == BlockState::Free && (*b).size >= size) {
-                if (*b).next.is_null() {
+                if (*b).next().is_null() {
                     dbg!(*b);
 
                     return b;
                 }
-                b = (*b).next as *mut _;
+                b = (*b).next();
+            }
+            if !b.is_null() && (*b).free == BlockState::Free {
+                crate::poison::verify(b, "malloc");
             }
             dbg!(*b);
             b
         }
     }
 
+    /// Pick one of the first `RANDOM_CANDIDATES` free blocks in the same power
+    /// of two size class as `size` at random, null if there are none.
+    ///
+    /// # Safety
+    /// The heap starting at `base` must be valid.
+    unsafe fn pick_random(base: *mut Block, size: usize) -> *mut Block {
+        let rng = random::global();
+        let class = size.leading_zeros();
+        let mut pick = ptr::null_mut();
+        let mut seen = 0;
+        let mut b = base;
+        while !b.is_null() {
+            if (*b).free == BlockState::Free
+                && (*b).size >= size
+                && (*b).size.leading_zeros() == class
+            {
+                // Reservoir sampling, every candidate is equally likely
+                seen += 1;
+                if rng.below(seen) == 0 {
+                    pick = b;
+                }
+                // Don't walk the whole heap on every allocation
+                if seen == RANDOM_CANDIDATES {
+                    break;
+                }
+            }
+            b = (*b).next();
+        }
+        pick
+    }
+
+    /// Grow the heap at `brk` by a block with room for `size` bytes after `last`,
+    /// null if `brk` can't grow that far.
     ///
     /// # Safety
     /// It ain't
-    pub unsafe fn extend_heap(last: *mut Block, size: usize) -> *mut Block {
+    pub unsafe fn extend_heap<B: Break>(brk: &mut B, last: *mut Block, size: usize) -> *mut Block {
         let need_size = align(BLOCK_SIZE + size);
         let size = need_size as usize - BLOCK_SIZE;
-        // Returns pointer to the next free chunk
-        let mut b = sbrk(BLOCK_SIZE as isize)
-            .ok()
-            .map(|ptr| Block::from_raw(ptr as *mut _, size, last))
-            .unwrap();
-
-        if sbrk(need_size).is_ok() {
+        if last.is_null() {
+            // The program break we start with is not always word aligned
+            let end = match brk.sbrk(0) {
+                Ok(end) => end as usize,
+                Err(()) => return ptr::null_mut(),
+            };
+            if brk.sbrk(align(end) - end as isize).is_err() {
+                return ptr::null_mut();
+            }
+        }
+        // Returns pointer to the next free chunk, the header and data come from
+        // one request so blocks sit back to back and `absorb` can merge them.
+        if let Ok(ptr) = brk.sbrk(need_size) {
+            let b = Block::from_raw(ptr as *mut _, size, last);
             if !last.is_null() {
-                (*last).next = b.data;
+                (*last).set_next(b.data);
             }
             b.data
         } else {
-            panic!("NEXT PAGE IS OOM??")
+            ptr::null_mut()
         }
     }
 
@@ -154,18 +336,22 @@ impl Block {
     /// It ain't
     pub unsafe fn absorb(ptr: *mut Block) -> *mut Block {
         if !ptr.is_null() {
-            let mut blk = *ptr;
             // If we have a non null and free block absorb it
-            if !blk.next.is_null() && (*blk.next).free == BlockState::Free {
+            let next = (*ptr).next();
+            if !next.is_null() && (*next).free == BlockState::Free {
                 dbg!(*ptr);
-                (*ptr).size += BLOCK_SIZE + (*(*ptr).next).size;
-                (*ptr).next = (*(*ptr).next).next;
+                crate::poison::verify(next, "free");
+                (*ptr).size += BLOCK_SIZE + (*next).size;
+                (*ptr).set_next((*next).next());
+                if crate::poison::ENABLED {
+                    // The header of `next` becomes part of our data
+                    crate::poison::fill(next.cast(), BLOCK_SIZE);
+                }
 
                 // Now set "current" to prev for the newly "next" blk
-                if !(*ptr).next.is_null() {
-                    (*(*ptr).next).prev = ptr;
+                if !(*ptr).next().is_null() {
+                    (*(*ptr).next()).set_prev(ptr);
                 }
-                dbg!(&*crate::GLOBAL_BASE);
                 dbg!(&ptr);
             }
         }
@@ -185,14 +371,16 @@ impl Block {
         )
         .as_raw();
         // New's next is the old ptr's next
-        (*new).next = (*ptr).next;
+        (*new).set_next((*ptr).next());
+        if !(*new).next().is_null() {
+            (*(*new).next()).set_prev(new);
+        }
         // Since we are not filling the new block mark it as free
         (*new).free = BlockState::Free;
 
         (*ptr).size = size;
         // new is Block.data (pointer to itself) so this works
-        (*ptr).next = new;
-        dbg!(&*crate::GLOBAL_BASE);
+        (*ptr).set_next(new);
         dbg!(size);
     }
 
@@ -200,3 +388,26 @@ impl Block {
         ptr::copy_nonoverlapping(src.add(1).cast::<u8>(), dst.add(1).cast::<u8>(), count)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn block_links() {
+        init_link_secret();
+        let mut buf = [0_usize; 32];
+        unsafe {
+            let a = buf.as_mut_ptr().cast::<Block>();
+            let b = a.cast::<u8>().add(BLOCK_SIZE * 2).cast::<Block>();
+            Block::from_raw(a.cast(), BLOCK_SIZE, ptr::null_mut());
+            Block::from_raw(b.cast(), BLOCK_SIZE, a);
+            (*a).set_next(b);
+
+            assert_eq!((*a).next(), b);
+            assert_eq!((*b).prev(), a);
+            assert!((*a).prev().is_null());
+            assert!((*b).next().is_null());
+        }
+    }
+}
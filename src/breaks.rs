@@ -2,18 +2,126 @@
 
 use core::ptr;
 
-use crate::syscall;
+use crate::{
+    cgroup,
+    mmap::{self, MADV_DONTNEED, PROT_NONE, PROT_READ, PROT_WRITE},
+    random::{self, RANDOMIZE},
+    stats::{self, Syscall},
+    syscall,
+    util::{page_align, PAGE_SIZE},
+};
+
+/// How much address space is reserved for the heap when its base is randomized.
+const REGION_SIZE: usize = 1 << 36;
+/// The randomized base is picked from `REGION_LOW..REGION_LOW + REGION_SPREAD`.
+const REGION_LOW: usize = 0x1000_0000_0000;
+const REGION_SPREAD: usize = 0x4000_0000_0000;
 
 static mut BRK: BrkState = BrkState {
     current: ptr::null(),
 };
 
+static mut REGION: Region = Region {
+    base: ptr::null_mut(),
+    current: ptr::null_mut(),
+    mapped: ptr::null_mut(),
+};
+
 // TODO meaning full error (it's oom or nothing)
 /// The size of the requested allocation.
 ///
 /// This must include the `ralloc::Block` size and any other meta data/optimization stuff.
 pub unsafe fn sbrk(size: isize) -> Result<*const u8, ()> {
-    BRK.sbrk(size)
+    if size > 0 && !cgroup::may_grow(size as usize) {
+        return Err(());
+    }
+    let old = if RANDOMIZE {
+        REGION.sbrk(size)
+    } else {
+        BRK.sbrk(size)
+    }?;
+    stats::heap_moved(size);
+    Ok(old)
+}
+
+/// Somewhere a heap can grow and shrink at the end, like the program break.
+pub trait Break {
+    /// Move the end by `size` bytes and return where it was.
+    ///
+    /// # Safety
+    /// Shrinking gives memory back, nothing may use it after.
+    unsafe fn sbrk(&mut self, size: isize) -> Result<*const u8, ()>;
+
+    /// Move the end back to `end` giving everything after it back.
+    ///
+    /// # Safety
+    /// See `sbrk`.
+    unsafe fn shrink_to(&mut self, end: *const u8) -> Result<(), ()> {
+        let cur = self.sbrk(0)?;
+        self.sbrk(end as isize - cur as isize).map(|_| ())
+    }
+}
+
+/// The process's program break, or the randomized region standing in for it.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramBreak;
+
+impl Break for ProgramBreak {
+    unsafe fn sbrk(&mut self, size: isize) -> Result<*const u8, ()> {
+        sbrk(size)
+    }
+}
+
+/// A fake program break inside a large `PROT_NONE` reservation at a random address.
+///
+/// The real program break sits right after the executable so finding the heap
+/// is easy, this makes an attacker guess. Pages are made readable and writable
+/// as the break passes them.
+struct Region {
+    base: *mut u8,
+    current: *mut u8,
+    /// Everything from `base` up to here is readable and writable.
+    mapped: *mut u8,
+}
+
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    unsafe fn sbrk(&mut self, size: isize) -> Result<*const u8, ()> {
+        if self.base.is_null() {
+            self.reserve()?;
+        }
+        let old = self.current;
+        let new = old as isize + size;
+        if new < self.base as isize || new > self.base as isize + REGION_SIZE as isize {
+            return Err(());
+        }
+        let new = new as *mut u8;
+
+        let mapped = (self.base as usize + page_align(new as usize - self.base as usize)) as *mut u8;
+        if mapped > self.mapped {
+            let len = mapped as usize - self.mapped as usize;
+            mmap::protect(self.mapped, len, PROT_READ | PROT_WRITE)?;
+        } else if mapped < self.mapped {
+            let len = self.mapped as usize - mapped as usize;
+            mmap::protect(mapped, len, PROT_NONE)?;
+            let _ = mmap::advise(mapped, len, MADV_DONTNEED);
+        }
+        self.mapped = mapped;
+        self.current = new;
+        Ok(old)
+    }
+
+    /// Reserve the address space at a random page aligned address.
+    unsafe fn reserve(&mut self) -> Result<(), ()> {
+        let hint = (REGION_LOW + random::secret() as usize % REGION_SPREAD) & !(PAGE_SIZE - 1);
+        let base = mmap::reserve(hint as *const u8, REGION_SIZE)?;
+        self.base = base;
+        self.current = base;
+        self.mapped = base;
+        Ok(())
+    }
 }
 
 struct BrkState {
@@ -65,5 +173,6 @@ fn current_brk() -> *const u8 {
 
 // #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 pub unsafe fn brk(ptr: *const u8) -> *const u8 {
+    stats::count(Syscall::Brk);
     syscall!(BRK, ptr) as *const u8
 }
@@ -0,0 +1,265 @@
+//! Staying inside the container 📦
+//!
+//! In a cgroup `brk` and `mmap` keep working right up until the OOM killer
+//! shows up. With the `cgroup` feature the memory limits of our cgroup are read
+//! the first time we allocate, `memory.max` and `memory.high` for cgroup v2 or
+//! `memory.limit_in_bytes` for v1, using raw syscalls so nothing is allocated.
+//!
+//! Once the heap is past `memory.high`, or 7/8 of the max when there is no high,
+//! the pages of free blocks are handed back to the kernel with
+//! `MADV_DONTNEED` every time another `TRIM_STEP` bytes are freed. The heap is
+//! never grown past 15/16 of the max, the allocation fails instead and the
+//! rest is left for stacks, code and everything else the process maps.
+//!
+//! Only the size of our own heap is compared against the limits, the
+//! allocator assumes it is what fills the cgroup.
+
+use crate::{
+    block::{BlockState, BLOCK_SIZE},
+    mmap::{self, MADV_DONTNEED},
+    poison, stats, syscall,
+    util::{page_align, page_floor},
+};
+
+/// Are the cgroup memory limits read and kept to.
+pub const ENABLED: bool = cfg!(feature = "cgroup");
+
+/// Bytes freed above the high mark before free pages are given back again.
+pub const TRIM_STEP: usize = 1 << 20;
+
+/// Where the cgroup file systems are mounted.
+const CGROUP_ROOT: &[u8] = b"/sys/fs/cgroup";
+/// Where the v1 memory controller is mounted.
+const CGROUP_V1_ROOT: &[u8] = b"/sys/fs/cgroup/memory";
+/// cgroup v1 has no "max", unlimited is a number close to `i64::MAX`.
+const V1_UNLIMITED: usize = 1 << 62;
+const O_RDONLY: usize = 0;
+const O_CLOEXEC: usize = 0o2000000;
+
+static mut CGROUP: Cgroup = Cgroup {
+    read: false,
+    limits: CgroupLimits {
+        max: None,
+        high: None,
+    },
+    trim_above: usize::MAX,
+    fail_above: usize::MAX,
+    freed: 0,
+};
+
+/// The memory limits of the cgroup we run in, `None` is unlimited.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CgroupLimits {
+    /// `memory.max`, or `memory.limit_in_bytes` for cgroup v1.
+    pub max: Option<usize>,
+    /// `memory.high`, cgroup v1 doesn't have one.
+    pub high: Option<usize>,
+}
+
+struct Cgroup {
+    read: bool,
+    limits: CgroupLimits,
+    /// Free pages are given back while the heap is bigger than this.
+    trim_above: usize,
+    /// The heap never grows bigger than this.
+    fail_above: usize,
+    /// Bytes freed since free pages were last given back.
+    freed: usize,
+}
+
+/// Read the limits of our cgroup if they have not been read yet.
+pub fn init() {
+    unsafe {
+        if !ENABLED || CGROUP.read {
+            return;
+        }
+        CGROUP.read = true;
+        let limits = read_limits();
+        CGROUP.limits = limits;
+        if let Some(max) = limits.max {
+            CGROUP.fail_above = max - max / 16;
+            CGROUP.trim_above = max - max / 8;
+        }
+        if let Some(high) = limits.high {
+            CGROUP.trim_above = CGROUP.trim_above.min(high);
+        }
+    }
+}
+
+/// The memory limits of the cgroup this process is in, both are `None` without
+/// the `cgroup` feature.
+pub fn cgroup_limits() -> CgroupLimits {
+    init();
+    unsafe { CGROUP.limits }
+}
+
+/// Can the heap grow by `size` bytes and stay clear of `memory.max`.
+pub fn may_grow(size: usize) -> bool {
+    !ENABLED || stats::heap_size().saturating_add(size) <= unsafe { CGROUP.fail_above }
+}
+
+/// `size` bytes were freed, give free pages back if we are over the high mark.
+pub fn on_free(size: usize) {
+    unsafe {
+        if !ENABLED || stats::heap_size() <= CGROUP.trim_above {
+            return;
+        }
+        CGROUP.freed += size;
+        if CGROUP.freed >= TRIM_STEP {
+            CGROUP.freed = 0;
+            trim();
+        }
+    }
+}
+
+/// Give the whole pages inside every free block back to the kernel, they
+/// come back zeroed the next time they are touched.
+fn trim() {
+    // Poisoned memory is checked when it is reused, zeros would fail that
+    if poison::ENABLED {
+        return;
+    }
+    unsafe {
+        for b in crate::HEAP.blocks() {
+            if (*b).free != BlockState::Free {
+                continue;
+            }
+            let data = b as usize + BLOCK_SIZE;
+            let (start, end) = (page_align(data), page_floor(data + (*b).size));
+            if end > start {
+                let _ = mmap::advise(start as *mut u8, end - start, MADV_DONTNEED);
+            }
+        }
+    }
+}
+
+fn read_limits() -> CgroupLimits {
+    let mut buf = [0_u8; 4096];
+    let mut path = [0_u8; 512];
+    let procs = match read_file(b"/proc/self/cgroup\0", &mut buf) {
+        Some(procs) => procs,
+        None => return CgroupLimits::default(),
+    };
+    let (v1, len) = match memory_cgroup(procs) {
+        Some((v1, cgroup)) if cgroup.len() < path.len() => {
+            path[..cgroup.len()].copy_from_slice(cgroup);
+            (v1, cgroup.len())
+        }
+        _ => return CgroupLimits::default(),
+    };
+    let cgroup = &path[..len];
+
+    // Without a cgroup namespace our cgroup may not be where /proc says, the
+    // container runtime mounted it at the root
+    let read = |file: &[u8], buf: &mut [u8]| {
+        let root = if v1 { CGROUP_V1_ROOT } else { CGROUP_ROOT };
+        let mut name = [0_u8; 640];
+        if let Some(contents) = read_file(join(&mut name, &[root, cgroup, b"/", file])?, buf) {
+            return parse_limit(contents);
+        }
+        read_file(join(&mut name, &[root, b"/", file])?, buf).and_then(parse_limit)
+    };
+    if v1 {
+        CgroupLimits {
+            max: read(b"memory.limit_in_bytes", &mut buf),
+            high: None,
+        }
+    } else {
+        CgroupLimits {
+            max: read(b"memory.max", &mut buf),
+            high: read(b"memory.high", &mut buf),
+        }
+    }
+}
+
+/// Find the cgroup with our memory controller in the contents of
+/// `/proc/self/cgroup`, returns if it is cgroup v1 and the cgroup's path.
+fn memory_cgroup(procs: &[u8]) -> Option<(bool, &[u8])> {
+    let mut v2 = None;
+    for line in procs.split(|b| *b == b'\n') {
+        // hierarchy-ID:controller-list:cgroup-path
+        let mut fields = line.splitn(3, |b| *b == b':');
+        let (id, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+            _ => continue,
+        };
+        if controllers.split(|b| *b == b',').any(|c| c == b"memory") {
+            return Some((true, path));
+        }
+        if id == b"0" && controllers.is_empty() {
+            v2 = Some((false, path));
+        }
+    }
+    v2
+}
+
+/// A limit from a cgroup file, "max" or a v1 limit that is too big to mean
+/// anything are `None`.
+fn parse_limit(contents: &[u8]) -> Option<usize> {
+    let digits = contents.split(|b| *b == b'\n').next()?;
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let limit = digits
+        .iter()
+        .try_fold(0_usize, |n, d| n.checked_mul(10)?.checked_add((d - b'0') as usize))?;
+    if limit >= V1_UNLIMITED {
+        None
+    } else {
+        Some(limit)
+    }
+}
+
+/// Concatenate `parts` into `out` with a nul at the end.
+fn join<'a>(out: &'a mut [u8], parts: &[&[u8]]) -> Option<&'a [u8]> {
+    let mut len = 0;
+    for part in parts {
+        out.get_mut(len..len + part.len())?.copy_from_slice(part);
+        len += part.len();
+    }
+    *out.get_mut(len)? = 0;
+    Some(&out[..=len])
+}
+
+/// Read as much of the file at `path`, which ends with a nul, as fits in `buf`.
+fn read_file<'a>(path: &[u8], buf: &'a mut [u8]) -> Option<&'a [u8]> {
+    unsafe {
+        let fd = syscall!(OPEN, path.as_ptr(), O_RDONLY | O_CLOEXEC);
+        if syscall::is_err(fd) {
+            return None;
+        }
+        let mut len = 0;
+        while len < buf.len() {
+            let n = syscall!(READ, fd, buf[len..].as_mut_ptr(), buf.len() - len);
+            if syscall::is_err(n) || n == 0 {
+                break;
+            }
+            len += n;
+        }
+        syscall!(CLOSE, fd);
+        Some(&buf[..len])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cgroup_files() {
+        let v2 = b"0::/user.slice/app.scope\n";
+        assert_eq!(memory_cgroup(v2), Some((false, &b"/user.slice/app.scope"[..])));
+        let v1 = b"12:cpu,cpuacct:/\n11:memory:/docker/abc\n0::/\n";
+        assert_eq!(memory_cgroup(v1), Some((true, &b"/docker/abc"[..])));
+        assert_eq!(memory_cgroup(b"garbage"), None);
+
+        assert_eq!(parse_limit(b"max\n"), None);
+        assert_eq!(parse_limit(b"536870912\n"), Some(512 << 20));
+        assert_eq!(parse_limit(b"9223372036854771712\n"), None);
+        assert_eq!(parse_limit(b"99999999999999999999999"), None);
+
+        let mut buf = [0_u8; 16];
+        assert_eq!(join(&mut buf, &[b"/a", b"/", b"b"]), Some(&b"/a/b\0"[..]));
+        assert_eq!(join(&mut buf, &[&[b'x'; 16]]), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! Keeping tabs 🧾 on what a closure allocates.
+//!
+//! Every allocation and deallocation is counted per thread, `count_allocs`
+//! reports what happened while its closure ran and `assert_no_alloc` aborts the
+//! moment its closure allocates. Only the calling thread is watched, work handed
+//! to another thread is not counted.
+//!
+//! ```ignore
+//! let counts = ralloc::count_allocs(|| parser.parse(line));
+//! assert_eq!(counts.allocs, 0);
+//!
+//! ralloc::assert_no_alloc(|| ring.push(sample));
+//! ```
+//!
+//! A `realloc` counts as an allocation and a deallocation, it always moves.
+//! Allocations that fail are not counted. While a panic unwinds out of
+//! `assert_no_alloc` allocating is allowed, the panic needs to.
+
+use core::{alloc::Layout, fmt::Write};
+
+use crate::{
+    backtrace::{self, Stack},
+    report::{RawFd, STDERR},
+};
+
+#[thread_local]
+static mut COUNTS: AllocCounts = AllocCounts {
+    allocs: 0,
+    deallocs: 0,
+    bytes_allocated: 0,
+    bytes_deallocated: 0,
+};
+
+/// How many `assert_no_alloc` calls the current thread is inside.
+#[thread_local]
+static mut FORBIDDEN: usize = 0;
+
+/// What was allocated and freed, see `count_allocs`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AllocCounts {
+    pub allocs: usize,
+    pub deallocs: usize,
+    /// The sizes asked for, not counting headers or padding.
+    pub bytes_allocated: usize,
+    pub bytes_deallocated: usize,
+}
+
+impl AllocCounts {
+    fn since(self, before: AllocCounts) -> AllocCounts {
+        AllocCounts {
+            allocs: self.allocs - before.allocs,
+            deallocs: self.deallocs - before.deallocs,
+            bytes_allocated: self.bytes_allocated.wrapping_sub(before.bytes_allocated),
+            bytes_deallocated: self.bytes_deallocated.wrapping_sub(before.bytes_deallocated),
+        }
+    }
+}
+
+/// Run `f` and count what it allocated and freed on this thread.
+pub fn count_allocs(f: impl FnOnce()) -> AllocCounts {
+    let before = unsafe { COUNTS };
+    f();
+    unsafe { COUNTS }.since(before)
+}
+
+/// Leaves the `assert_no_alloc` even if the closure panics.
+struct Forbid;
+
+impl Drop for Forbid {
+    fn drop(&mut self) {
+        unsafe { FORBIDDEN -= 1 };
+    }
+}
+
+/// Run `f` and abort the process if it allocates on this thread.
+///
+/// Freeing is allowed. The diagnostic says what was asked for and, with the
+/// `backtrace` feature, where from.
+pub fn assert_no_alloc<R>(f: impl FnOnce() -> R) -> R {
+    unsafe { FORBIDDEN += 1 };
+    let _forbid = Forbid;
+    f()
+}
+
+/// About to allocate `layout`, aborts inside `assert_no_alloc` unless we are
+/// unwinding.
+pub fn check(layout: Layout) {
+    unsafe {
+        if FORBIDDEN != 0 && !std::thread::panicking() {
+            // Let the report allocate if it wants to
+            FORBIDDEN = 0;
+            forbidden(layout);
+        }
+    }
+}
+
+/// Count an allocation of `layout` that succeeded.
+pub fn on_alloc(layout: Layout) {
+    unsafe {
+        COUNTS.allocs += 1;
+        COUNTS.bytes_allocated = COUNTS.bytes_allocated.wrapping_add(layout.size());
+    }
+}
+
+/// Count a deallocation of `size` bytes.
+pub fn on_dealloc(size: usize) {
+    unsafe {
+        COUNTS.deallocs += 1;
+        COUNTS.bytes_deallocated = COUNTS.bytes_deallocated.wrapping_add(size);
+    }
+}
+
+#[cold]
+fn forbidden(layout: Layout) -> ! {
+    let mut out = RawFd(STDERR);
+    let _ = writeln!(
+        out,
+        "ralloc: allocation of {} bytes (align {}) inside assert_no_alloc",
+        layout.size(),
+        layout.align()
+    );
+    if backtrace::ENABLED {
+        let _ = write!(out, "ralloc:   at");
+        for frame in Stack::capture().frames() {
+            let _ = write!(out, " {:#x}", frame);
+        }
+        let _ = writeln!(out, "\nralloc: mappings:");
+        backtrace::write_maps(STDERR);
+    }
+    std::process::abort()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_on_this_thread() {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let counts = count_allocs(|| {
+            on_alloc(layout);
+            on_alloc(layout);
+            on_dealloc(24);
+            // Other threads are not counted
+            std::thread::spawn(move || on_alloc(layout)).join().unwrap();
+        });
+        assert_eq!(
+            counts,
+            AllocCounts {
+                allocs: 2,
+                deallocs: 1,
+                bytes_allocated: 48,
+                bytes_deallocated: 24,
+            }
+        );
+
+        // Freeing is fine and a panic leaves the scope
+        let one = assert_no_alloc(|| {
+            on_dealloc(8);
+            1
+        });
+        assert_eq!(one, 1);
+        let _ = std::panic::catch_unwind(|| assert_no_alloc(|| panic!("leaving")));
+        assert_eq!(unsafe { FORBIDDEN }, 0);
+    }
+}
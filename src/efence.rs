@@ -0,0 +1,245 @@
+//! Electric fence ⚡ every allocation gets its own pages and a guard page.
+//!
+//! ```notrust
+//! trailing  |___________|  data|guard|
+//! leading   |guard|data  |___________|
+//! ```
+//!
+//! With a trailing guard the data is pushed up against the guard page so any
+//! overflow faults on the offending instruction, a leading guard catches
+//! underflows instead. Freed pages are made `PROT_NONE` and never reused so any
+//! use after free faults too. The allocation's tag is kept in a byte at the
+//! other end of its pages, away from the guard.
+//!
+//! This uses a __lot__ of memory, it is for hunting down a specific bug. Turn it
+//! on with the `efence` feature or by setting `RALLOC_EFENCE` to `trailing` (or
+//! `1`) or `leading`, `RALLOC_EFENCE=0` (or `off`) turns it off even with the
+//! feature. Any other value is reported and ignored.
+
+use core::{alloc::Layout, cmp, ptr};
+
+use crate::{
+    mmap::{self, MADV_DONTNEED, PROT_NONE, PROT_READ, PROT_WRITE},
+    report,
+    util::{self, page_align, PAGE_SIZE},
+    wipe,
+};
+
+static mut GUARD: Guard = Guard::Unknown;
+
+/// Where the guard page goes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Guard {
+    /// We have not looked at the feature and environment yet.
+    Unknown,
+    Off,
+    Trailing,
+    Leading,
+}
+
+/// Is every allocation being fenced.
+pub fn enabled() -> bool {
+    guard() != Guard::Off
+}
+
+fn guard() -> Guard {
+    unsafe {
+        if GUARD == Guard::Unknown {
+            let env = util::env(b"RALLOC_EFENCE\0");
+            GUARD = parse(env).unwrap_or_else(|()| {
+                report::write(format_args!(
+                    "RALLOC_EFENCE must be trailing, leading, 1, 0 or off, ignoring {:?}",
+                    env.and_then(|v| core::str::from_utf8(v).ok()).unwrap_or("?")
+                ));
+                parse(None).unwrap()
+            });
+        }
+        GUARD
+    }
+}
+
+/// The guard asked for by the value of `RALLOC_EFENCE`, the feature decides
+/// when it isn't set.
+fn parse(env: Option<&[u8]>) -> Result<Guard, ()> {
+    match env {
+        Some(b"trailing") | Some(b"1") => Ok(Guard::Trailing),
+        Some(b"leading") => Ok(Guard::Leading),
+        Some(b"0") | Some(b"off") => Ok(Guard::Off),
+        Some(_) => Err(()),
+        None if cfg!(feature = "efence") => Ok(Guard::Trailing),
+        None => Ok(Guard::Off),
+    }
+}
+
+/// The number of bytes of data actually handed out for `layout`.
+///
+/// Rounding up to the alignment keeps a trailing fenced pointer aligned.
+fn data_size(layout: Layout) -> usize {
+    let align = layout.align();
+    (cmp::max(layout.size(), 1) + align - 1) & !(align - 1)
+}
+
+/// The number of bytes of pages mapped for `layout` without the guard, with
+/// room for the tag.
+fn data_len(layout: Layout) -> usize {
+    page_align(data_size(layout) + 1)
+}
+
+/// Map fresh pages for `layout` tagged `tag` with a guard page on one side.
+///
+/// # Safety
+/// `enabled()` must be true.
+pub unsafe fn alloc(layout: Layout, tag: u8) -> *mut u8 {
+    let ptr = alloc_with(layout, guard());
+    if !ptr.is_null() {
+        *tag_at(ptr, layout, guard()) = tag;
+    }
+    ptr
+}
+
+/// The tag `ptr` was allocated with.
+///
+/// # Safety
+/// `ptr` must have been returned by `alloc` with `layout` and not freed.
+pub unsafe fn tag(ptr: *mut u8, layout: Layout) -> u8 {
+    *tag_at(ptr, layout, guard())
+}
+
+/// Where the tag of `ptr` is kept, the first byte of the mapping with a
+/// trailing guard and the last one before the next mapping with a leading one.
+fn tag_at(ptr: *mut u8, layout: Layout, guard: Guard) -> *mut u8 {
+    let start = start(ptr, layout, guard);
+    match guard {
+        Guard::Leading => start.wrapping_add(PAGE_SIZE + data_len(layout) - 1),
+        _ => start,
+    }
+}
+
+/// The start of the mapping `ptr` was handed out from.
+fn start(ptr: *mut u8, layout: Layout, guard: Guard) -> *mut u8 {
+    match guard {
+        Guard::Leading => ptr.wrapping_sub(PAGE_SIZE),
+        // The data ends where the guard starts
+        _ => ptr.wrapping_add(data_size(layout)).wrapping_sub(data_len(layout)),
+    }
+}
+
+unsafe fn alloc_with(layout: Layout, guard: Guard) -> *mut u8 {
+    if layout.align() > PAGE_SIZE {
+        return ptr::null_mut();
+    }
+    let size = data_size(layout);
+    let data_len = data_len(layout);
+    let start = match mmap::map(ptr::null(), data_len + PAGE_SIZE, PROT_READ | PROT_WRITE) {
+        Ok(start) => start,
+        Err(()) => return ptr::null_mut(),
+    };
+
+    let (guard, data) = match guard {
+        Guard::Leading => (start, start.add(PAGE_SIZE)),
+        _ => (start.add(data_len), start.add(data_len - size)),
+    };
+    if mmap::protect(guard, PAGE_SIZE, PROT_NONE).is_err() {
+        let _ = mmap::unmap(start, data_len + PAGE_SIZE);
+        return ptr::null_mut();
+    }
+    data
+}
+
+/// Make the pages of `ptr` inaccessible forever.
+///
+/// # Safety
+/// `ptr` must have been returned by `alloc` with `layout`.
+pub unsafe fn free(ptr: *mut u8, layout: Layout) {
+    free_with(ptr, layout, guard())
+}
+
+unsafe fn free_with(ptr: *mut u8, layout: Layout, guard: Guard) {
+    let data_len = data_len(layout);
+    let start = start(ptr, layout, guard);
+    if wipe::enabled() {
+        wipe::wipe(ptr, layout.size());
+    }
+    if mmap::protect(start, data_len + PAGE_SIZE, PROT_NONE).is_err() {
+        report::abort(format_args!(
+            "invalid free of fenced pointer {:?} with size {}",
+            ptr,
+            layout.size()
+        ));
+    }
+    // Keep the address range so it is never reused but let the memory go
+    let _ = mmap::advise(start, data_len + PAGE_SIZE, MADV_DONTNEED);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::page_floor;
+
+    /// Does writing to `addr` kill a forked child with `SIGSEGV`.
+    fn faults(addr: *mut u8) -> bool {
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                addr.write_volatile(1);
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+        }
+    }
+
+    #[test]
+    fn parse_env() {
+        assert_eq!(parse(Some(b"trailing")), Ok(Guard::Trailing));
+        assert_eq!(parse(Some(b"1")), Ok(Guard::Trailing));
+        assert_eq!(parse(Some(b"leading")), Ok(Guard::Leading));
+        assert_eq!(parse(Some(b"off")), Ok(Guard::Off));
+        assert_eq!(parse(Some(b"")), Err(()));
+        assert_eq!(parse(Some(b"trialing")), Err(()));
+    }
+
+    #[test]
+    fn trailing_guard() {
+        let layout = Layout::from_size_align(100, 4).unwrap();
+        unsafe {
+            let ptr = alloc_with(layout, Guard::Trailing);
+            assert_eq!((ptr as usize + 100) % PAGE_SIZE, 0);
+            assert!(!faults(ptr.add(99)));
+            assert!(faults(ptr.add(100)));
+            assert_eq!(tag_at(ptr, layout, Guard::Trailing), ptr.sub(PAGE_SIZE - 100));
+            free_with(ptr, layout, Guard::Trailing);
+        }
+    }
+
+    #[test]
+    fn leading_guard() {
+        let layout = Layout::from_size_align(100, 16).unwrap();
+        unsafe {
+            let ptr = alloc_with(layout, Guard::Leading);
+            assert_eq!(ptr as usize % PAGE_SIZE, 0);
+            assert!(!faults(ptr));
+            assert!(faults(ptr.sub(1)));
+            assert_eq!(tag_at(ptr, layout, Guard::Leading), ptr.add(PAGE_SIZE - 1));
+            free_with(ptr, layout, Guard::Leading);
+        }
+    }
+
+    #[test]
+    fn freed_pages_stay_fenced() {
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        unsafe {
+            let old = alloc_with(layout, Guard::Trailing);
+            free_with(old, layout, Guard::Trailing);
+            assert!(faults(old));
+            let pages = page_floor(old as usize)..page_floor(old as usize) + 2 * PAGE_SIZE;
+            for _ in 0..64 {
+                let ptr = alloc_with(layout, Guard::Trailing);
+                assert!(!pages.contains(&(ptr as usize)));
+                free_with(ptr, layout, Guard::Trailing);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Allocations that fail on purpose 💥 so the out of memory paths get run.
+//!
+//! With the `fail` feature a test can pick a `FailPolicy` with
+//! `set_fail_policy` and the allocator returns null, or `AllocError` through
+//! `AllocRef`, whenever the policy says so. Nothing else about the allocation
+//! changes, a failed `realloc` leaves the old allocation where it was.
+//!
+//! The policy belongs to the thread that set it, tests running in parallel
+//! don't fail each other's allocations.
+
+use crate::random::Rng;
+
+/// Can allocations be made to fail.
+pub const ENABLED: bool = cfg!(feature = "fail");
+
+#[thread_local]
+static mut STATE: State = State {
+    policy: FailPolicy::Never,
+    allocs: 0,
+    bytes: 0,
+    failures: 0,
+    rng: Rng::new(0),
+};
+
+/// When allocations on this thread should fail, see `set_fail_policy`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailPolicy {
+    /// Only fail when we really are out of memory.
+    Never,
+    /// Fail every `n`th allocation, zero never fails.
+    EveryNth(usize),
+    /// Fail any allocation bigger than this many bytes.
+    AboveSize(usize),
+    /// Fail on average one in `one_in` allocations, the same `seed` fails the
+    /// same allocations every run.
+    Random { seed: u64, one_in: usize },
+    /// Succeed until this many bytes have been handed out then fail
+    /// everything that doesn't fit in what is left.
+    Budget(usize),
+}
+
+struct State {
+    policy: FailPolicy,
+    /// Allocations asked for since the policy was set.
+    allocs: usize,
+    /// Bytes handed out since the policy was set.
+    bytes: usize,
+    failures: usize,
+    rng: Rng,
+}
+
+/// Make allocations on the calling thread fail according to `policy`.
+///
+/// This resets the counts behind `EveryNth` and `Budget`. Without the `fail`
+/// feature the policy is remembered but nothing fails.
+pub fn set_fail_policy(policy: FailPolicy) {
+    unsafe {
+        STATE.policy = policy;
+        STATE.allocs = 0;
+        STATE.bytes = 0;
+        STATE.failures = 0;
+        if let FailPolicy::Random { seed, .. } = policy {
+            STATE.rng = Rng::new(seed);
+        }
+    }
+}
+
+/// How many allocations on the calling thread were failed on purpose since the
+/// policy was set.
+pub fn injected_failures() -> usize {
+    unsafe { STATE.failures }
+}
+
+/// Should an allocation of `size` bytes on this thread fail.
+pub fn should_fail(size: usize) -> bool {
+    let state = unsafe { &mut STATE };
+    state.allocs += 1;
+    let fail = match state.policy {
+        FailPolicy::Never => false,
+        FailPolicy::EveryNth(n) => n != 0 && state.allocs % n == 0,
+        FailPolicy::AboveSize(max) => size > max,
+        FailPolicy::Random { one_in, .. } => one_in != 0 && state.rng.below(one_in) == 0,
+        FailPolicy::Budget(budget) => budget - state.bytes < size,
+    };
+    if fail {
+        state.failures += 1;
+    } else {
+        state.bytes = state.bytes.saturating_add(size);
+    }
+    fail
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fail_policies() {
+        set_fail_policy(FailPolicy::EveryNth(3));
+        let fails: Vec<_> = (0..6).map(|_| should_fail(8)).collect();
+        assert_eq!(fails, [false, false, true, false, false, true]);
+        assert_eq!(injected_failures(), 2);
+
+        set_fail_policy(FailPolicy::AboveSize(64));
+        assert!(!should_fail(64));
+        assert!(should_fail(65));
+
+        set_fail_policy(FailPolicy::Budget(100));
+        assert!(!should_fail(60));
+        assert!(should_fail(60));
+        assert!(!should_fail(40));
+        assert!(should_fail(1));
+
+        // The same seed fails the same allocations
+        let run = |seed| {
+            set_fail_policy(FailPolicy::Random { seed, one_in: 4 });
+            (0..64).map(|_| should_fail(8)).collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert!(injected_failures() > 0 && injected_failures() < 64);
+
+        set_fail_policy(FailPolicy::Never);
+        assert!(!should_fail(usize::MAX));
+    }
+
+    #[test]
+    #[cfg(feature = "fail")]
+    fn alloc_ref_fails() {
+        use core::alloc::{AllocRef, GlobalAlloc, Layout};
+
+        set_fail_policy(FailPolicy::AboveSize(0));
+        let layout = Layout::new::<u64>();
+        assert!(AllocRef::alloc(&&crate::Ralloc, layout).is_err());
+        assert!(unsafe { GlobalAlloc::alloc(&crate::Ralloc, layout) }.is_null());
+        assert_eq!(injected_failures(), 2);
+        set_fail_policy(FailPolicy::Never);
+    }
+}
@@ -0,0 +1,416 @@
+//! Sampled guard page allocations, a tiny GWP-ASan 💂
+//!
+//! Every so often an allocation is served from a pool where each page of data
+//! is surrounded by `PROT_NONE` guard pages.
+//!
+//! ```notrust
+//! |guard|__slot 0__|guard|__slot 1__|guard| ... |guard|
+//!            |data|
+//! ```
+//!
+//! The data is pushed up against the guard page after it so an overflow faults
+//! right away. When an allocation is freed its slot is made inaccessible and is
+//! not handed out again until every other slot has been, so a use after free
+//! faults too. The `SIGSEGV` handler turns any fault in the pool into a report.
+//!
+//! Sampling is rare enough that this can be left on in production.
+
+use core::{alloc::Layout, ptr};
+
+use crate::{
+    mmap::{self, PROT_NONE, PROT_READ, PROT_WRITE},
+    random, report,
+    signal::{self, SigAction, SigInfo, SIGSEGV},
+    util::PAGE_SIZE,
+    wipe,
+};
+
+/// Are allocations sampled into the guarded pool.
+pub const ENABLED: bool = cfg!(feature = "guarded");
+
+/// On average one in this many allocations is guarded unless changed with
+/// `set_guard_sample_rate`.
+pub const DEFAULT_SAMPLE_RATE: usize = 5000;
+
+/// The number of allocations that can be guarded at once.
+const SLOTS: usize = 64;
+
+/// Every slot has a guard page before it and there is one more at the end.
+const POOL_SIZE: usize = (SLOTS * 2 + 1) * PAGE_SIZE;
+
+static mut POOL: Pool = Pool {
+    base: ptr::null_mut(),
+    slots: [Slot::EMPTY; SLOTS],
+    next: 0,
+    countdown: DEFAULT_SAMPLE_RATE,
+    rate: DEFAULT_SAMPLE_RATE,
+    failed: false,
+};
+
+/// The `SIGSEGV` action that was installed before ours.
+static mut OLD_ACTION: SigAction = SigAction::empty();
+
+/// Set how often allocations are guarded, on average one in every `rate`.
+///
+/// A rate of zero turns sampling off.
+pub fn set_guard_sample_rate(rate: usize) {
+    unsafe {
+        POOL.rate = rate;
+        POOL.countdown = rate;
+    }
+}
+
+/// Does `ptr` point into the guarded pool.
+pub fn contains(ptr: *const u8) -> bool {
+    let base = unsafe { POOL.base } as usize;
+    let addr = ptr as usize;
+    base != 0 && addr >= base && addr < base + POOL_SIZE
+}
+
+/// Maybe serve `layout` tagged `tag` from the guarded pool.
+///
+/// Returns null if this allocation was not sampled or can't be guarded, the
+/// caller should carry on as normal.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn alloc(layout: Layout, tag: u8) -> *mut u8 {
+    let pool = &mut POOL;
+    if pool.rate == 0 || layout.size() == 0 || layout.size() + layout.align() > PAGE_SIZE {
+        return ptr::null_mut();
+    }
+    pool.countdown -= 1;
+    if pool.countdown != 0 {
+        return ptr::null_mut();
+    }
+    // Random intervals with a mean of `rate` so the pattern can't be gamed
+    pool.countdown = random::global().below(pool.rate * 2 - 1) + 1;
+
+    if pool.base.is_null() && !pool.init() {
+        return ptr::null_mut();
+    }
+
+    for _ in 0..SLOTS {
+        let idx = pool.next;
+        pool.next = (pool.next + 1) % SLOTS;
+        if pool.slots[idx].state == SlotState::InUse {
+            continue;
+        }
+
+        let page = pool.page(idx);
+        if mmap::protect(page, PAGE_SIZE, PROT_READ | PROT_WRITE).is_err() {
+            return ptr::null_mut();
+        }
+        let data = (page as usize + PAGE_SIZE - layout.size()) & !(layout.align() - 1);
+        pool.slots[idx] = Slot {
+            state: SlotState::InUse,
+            ptr: data as *mut u8,
+            size: layout.size(),
+            tag,
+        };
+        return data as *mut u8;
+    }
+    ptr::null_mut()
+}
+
+/// The tag a guarded allocation was made with.
+///
+/// # Safety
+/// `contains(ptr)` must be true.
+pub unsafe fn tag(ptr: *mut u8) -> u8 {
+    let page = (ptr as usize - POOL.base as usize) / PAGE_SIZE;
+    POOL.slots[page / 2].tag
+}
+
+/// Free a guarded allocation, the page is made inaccessible.
+///
+/// # Safety
+/// `contains(ptr)` must be true.
+pub unsafe fn free(ptr: *mut u8, layout: Layout) {
+    let pool = &mut POOL;
+    let page = (ptr as usize - pool.base as usize) / PAGE_SIZE;
+    if page % 2 == 0 {
+        report::abort(format_args!(
+            "invalid free of {:?} which is a guard page",
+            ptr
+        ));
+    }
+    let slot = &mut pool.slots[page / 2];
+    if slot.state != SlotState::InUse || slot.ptr != ptr {
+        let what = if slot.state == SlotState::Freed && slot.ptr == ptr {
+            "double free"
+        } else {
+            "invalid free"
+        };
+        report::abort(format_args!(
+            "{} of guarded pointer {:?} with size {}",
+            what,
+            ptr,
+            layout.size()
+        ));
+    }
+    slot.state = SlotState::Freed;
+    if wipe::enabled() {
+        wipe::wipe(ptr, slot.size);
+    }
+    let _ = mmap::protect(pool.page(page / 2), PAGE_SIZE, PROT_NONE);
+}
+
+/// Our `SIGSEGV` handler, faults outside of the pool are passed on to whoever
+/// was handling them before us.
+extern "C" fn on_segv(_sig: i32, info: *mut SigInfo, _ctx: *mut u8) {
+    unsafe {
+        let addr = (*info).addr;
+        if contains(addr as *const u8) {
+            POOL.report(addr);
+        }
+        // Not ours, put the old action back and return, the access faults again
+        // and is handled as if we were never here.
+        let _ = signal::action(SIGSEGV, &OLD_ACTION);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SlotState {
+    /// This slot has never been used.
+    Empty,
+    InUse,
+    /// The allocation was freed and its page is `PROT_NONE`.
+    Freed,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    state: SlotState,
+    ptr: *mut u8,
+    size: usize,
+    tag: u8,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        state: SlotState::Empty,
+        ptr: ptr::null_mut(),
+        size: 0,
+        tag: 0,
+    };
+}
+
+struct Pool {
+    base: *mut u8,
+    slots: [Slot; SLOTS],
+    /// The next slot to try, we go round robin so freed slots stay
+    /// inaccessible for as long as possible.
+    next: usize,
+    /// Allocations left until the next one is sampled.
+    countdown: usize,
+    rate: usize,
+    /// Mapping the pool failed, don't keep trying.
+    failed: bool,
+}
+
+unsafe impl Send for Pool {}
+unsafe impl Sync for Pool {}
+
+impl Pool {
+    /// Map the pool and install the `SIGSEGV` handler.
+    unsafe fn init(&mut self) -> bool {
+        if self.failed {
+            return false;
+        }
+        let base = match mmap::map(ptr::null(), POOL_SIZE, PROT_NONE) {
+            Ok(base) => base,
+            Err(()) => {
+                self.failed = true;
+                return false;
+            }
+        };
+        match signal::action(SIGSEGV, &SigAction::new(on_segv)) {
+            Ok(old) => OLD_ACTION = old,
+            Err(()) => {
+                let _ = mmap::unmap(base, POOL_SIZE);
+                self.failed = true;
+                return false;
+            }
+        }
+        self.base = base;
+        true
+    }
+
+    /// The data page of slot `idx`.
+    fn page(&self, idx: usize) -> *mut u8 {
+        unsafe { self.base.add((idx * 2 + 1) * PAGE_SIZE) }
+    }
+
+    /// Explain the fault at `addr` which is inside the pool and abort.
+    unsafe fn report(&self, addr: usize) -> ! {
+        let page = (addr - self.base as usize) / PAGE_SIZE;
+        if page % 2 == 1 {
+            let slot = &self.slots[page / 2];
+            if slot.state == SlotState::Freed {
+                report::abort(format_args!(
+                    "use after free at {:#x}: {} bytes into the freed {} byte allocation at {:?}",
+                    addr,
+                    addr as isize - slot.ptr as isize,
+                    slot.size,
+                    slot.ptr
+                ));
+            }
+        } else {
+            // A guard page sits between the slot before it and the slot after it,
+            // data is pushed to the end of a slot so overflows are more likely.
+            let before = if page > 0 {
+                Some(&self.slots[page / 2 - 1])
+            } else {
+                None
+            };
+            let after = self.slots.get(page / 2);
+            if let Some(slot) = before.filter(|s| s.state != SlotState::Empty) {
+                report::abort(format_args!(
+                    "heap buffer overflow at {:#x}: {} bytes past the end of the {} byte \
+                     {}allocation at {:?}",
+                    addr,
+                    addr - (slot.ptr as usize + slot.size),
+                    slot.size,
+                    if slot.state == SlotState::Freed { "freed " } else { "" },
+                    slot.ptr
+                ));
+            }
+            if let Some(slot) = after.filter(|s| s.state != SlotState::Empty) {
+                report::abort(format_args!(
+                    "heap buffer underflow at {:#x}: {} bytes before the {} byte \
+                     {}allocation at {:?}",
+                    addr,
+                    slot.ptr as usize - addr,
+                    slot.size,
+                    if slot.state == SlotState::Freed { "freed " } else { "" },
+                    slot.ptr
+                ));
+            }
+        }
+        report::abort(format_args!(
+            "invalid access at {:#x} inside the guarded pool",
+            addr
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run `f` in a forked child and return its wait status and what it wrote
+    /// to stderr. The child must not allocate, the heap lock may be held by
+    /// another test thread when we fork.
+    fn in_child(f: fn()) -> (i32, String) {
+        unsafe {
+            let mut fds = [0; 2];
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                libc::dup2(fds[1], 2);
+                f();
+                libc::_exit(0);
+            }
+            libc::close(fds[1]);
+            let mut out = Vec::new();
+            let mut buf = [0_u8; 512];
+            loop {
+                let n = libc::read(fds[0], buf.as_mut_ptr().cast(), buf.len());
+                if n <= 0 {
+                    break;
+                }
+                out.extend_from_slice(&buf[..n as usize]);
+            }
+            libc::close(fds[0]);
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            (status, String::from_utf8(out).unwrap())
+        }
+    }
+
+    /// Check the child aborted after writing a report that starts with `what`.
+    fn assert_report(f: fn(), what: &str) {
+        let (status, out) = in_child(f);
+        let signal = unsafe { libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGABRT };
+        assert!(signal, "{:?}", out);
+        assert!(out.starts_with(&format!("ralloc: {} at ", what)), "{:?}", out);
+    }
+
+    /// A guarded allocation of `size` bytes, every allocation is sampled.
+    unsafe fn guarded(size: usize) -> *mut u8 {
+        set_guard_sample_rate(1);
+        let ptr = alloc(Layout::from_size_align(size, 1).unwrap(), 0);
+        if ptr.is_null() || !contains(ptr) {
+            libc::_exit(2);
+        }
+        ptr
+    }
+
+    #[test]
+    fn sampling() {
+        let (status, out) = in_child(|| unsafe {
+            set_guard_sample_rate(8);
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            let mut sampled = 0;
+            for _ in 0..8000 {
+                let ptr = alloc(layout, 0);
+                if !ptr.is_null() {
+                    sampled += 1;
+                    free(ptr, layout);
+                }
+            }
+            report::write(format_args!("{}", sampled));
+        });
+        assert_eq!(status, 0, "{:?}", out);
+        let sampled: usize = out.trim().trim_start_matches("ralloc: ").parse().unwrap();
+        assert!((500..1500).contains(&sampled), "{}", sampled);
+    }
+
+    #[test]
+    fn guard_layout() {
+        // The data ends where the guard page after it starts
+        let (status, out) = in_child(|| unsafe {
+            let ptr = guarded(100);
+            if (ptr as usize + 100) % PAGE_SIZE != 0 {
+                libc::_exit(3);
+            }
+            ptr.write_bytes(1, 100);
+        });
+        assert_eq!(status, 0, "{:?}", out);
+    }
+
+    #[test]
+    fn overflow() {
+        assert_report(
+            || unsafe { guarded(100).add(100).write_volatile(1) },
+            "heap buffer overflow",
+        );
+    }
+
+    #[test]
+    fn underflow() {
+        // The first slot is the only one in use so the guard page in front of
+        // it belongs to it
+        assert_report(
+            || unsafe {
+                let ptr = guarded(100);
+                ptr.sub(PAGE_SIZE - 100 + 1).write_volatile(1)
+            },
+            "heap buffer underflow",
+        );
+    }
+
+    #[test]
+    fn use_after_free() {
+        assert_report(
+            || unsafe {
+                let ptr = guarded(100);
+                free(ptr, Layout::from_size_align(100, 1).unwrap());
+                ptr.read_volatile();
+            },
+            "use after free",
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! A heap of `Block`s ⛰️ and the `Break` it grows from.
+//!
+//! The global allocator is one `Heap` on the program break. Tests, and anything
+//! built with the `sim` feature, can make their own on a `SimBreak`, each one is
+//! isolated from the others and none of them make a syscall.
+
+use core::{cmp, mem, ptr};
+
+use crate::{
+    block::{self, Block, BlockState},
+    breaks::Break,
+    poison,
+    util::{self, align},
+    wipe,
+};
+
+/// The largest request, anything bigger could overflow once the header is added.
+const MAX_SIZE: usize = isize::MAX as usize / 2;
+
+/// A linked list of `Block`s laid out back to back at the start of `brk`.
+pub struct Heap<B> {
+    /// The first block, null while the heap is empty.
+    pub base: *mut Block,
+    brk: B,
+    /// Wipe freed blocks even when `set_wipe_on_free` has it off for the process.
+    wipe: bool,
+}
+
+unsafe impl<B: Send> Send for Heap<B> {}
+unsafe impl<B: Sync> Sync for Heap<B> {}
+
+impl<B> Heap<B> {
+    /// An empty heap that will grow from `brk`.
+    pub const fn new(brk: B) -> Self {
+        Self {
+            base: ptr::null_mut(),
+            brk,
+            wipe: false,
+        }
+    }
+
+    /// Wipe what is freed from this heap, whatever `set_wipe_on_free` says for
+    /// the rest of the process.
+    pub fn set_wipe_on_free(&mut self, on: bool) {
+        self.wipe = on;
+    }
+
+    /// Is memory freed from this heap wiped.
+    fn wipes(&self) -> bool {
+        self.wipe || wipe::enabled()
+    }
+
+    /// Where the heap grows from.
+    pub fn brk(&self) -> &B {
+        &self.brk
+    }
+
+    /// Every block from the start of the heap to the end.
+    pub fn blocks(&self) -> Blocks {
+        Blocks { next: self.base }
+    }
+}
+
+impl<B: Break> Heap<B> {
+    /// Find or make a `Block` with room for `size` bytes and return a pointer to the data.
+    ///
+    /// Null if the break can't grow enough.
+    ///
+    /// # Safety
+    /// The heap must be valid.
+    pub unsafe fn malloc_block(&mut self, size: usize) -> *mut u8 {
+        // The header and alignment are added to `size`, that must not overflow
+        if size > MAX_SIZE {
+            return ptr::null_mut();
+        }
+        let size = align(size) as usize;
+        // This is our first alloc
+        if self.base.is_null() {
+            block::init_link_secret();
+            let blk = Block::extend_heap(&mut self.brk, ptr::null_mut(), size);
+            if blk.is_null() {
+                return ptr::null_mut();
+            }
+            self.base = blk;
+            (*blk).data.add(1) as *mut u8
+        } else {
+            // watch this when fixing ptr arithmetic this size is the data size not total
+            let blk_ptr = Block::find_block(self.base, size);
+            if blk_ptr.is_null() {
+                return ptr::null_mut();
+            }
+
+            // We need to extend the heap, `find_block` gave us the last block which
+            // is either in use, quarantined or too small.
+            if (*blk_ptr).free != BlockState::Free || (*blk_ptr).size < size {
+                let new = Block::extend_heap(&mut self.brk, blk_ptr, size);
+                if new.is_null() {
+                    return ptr::null_mut();
+                }
+                return (*new).data.add(1) as *mut u8;
+            }
+
+            // PTR MATH fix
+            let blk_size = (*blk_ptr).size;
+            if (blk_size as isize - size as isize) >= (block::BLOCK_SIZE + mem::size_of::<usize>()) as isize {
+                Block::split_block(blk_ptr, size);
+            }
+
+            (*blk_ptr).free = BlockState::InUse;
+            (*blk_ptr).data.add(1) as *mut u8
+        }
+    }
+
+    /// Like `malloc_block` but the data is aligned to `align`, a power of two.
+    ///
+    /// Blocks are only word aligned, for more we ask for enough extra to slide
+    /// the data up to the alignment and leave a free block in the gap.
+    ///
+    /// # Safety
+    /// The heap must be valid.
+    pub unsafe fn malloc_aligned(&mut self, size: usize, align: usize) -> *mut u8 {
+        if align <= mem::align_of::<usize>() {
+            return self.malloc_block(size);
+        }
+        let ptr = match size.checked_add(align + block::BLOCK_SIZE) {
+            Some(padded) => self.malloc_block(padded),
+            None => return ptr::null_mut(),
+        };
+        if ptr.is_null() {
+            return ptr;
+        }
+        let mut blk = Block::get_block(ptr);
+
+        if ptr as usize % align != 0 {
+            // The new header has to fit between `ptr` and the aligned data
+            let data = (ptr as usize + block::BLOCK_SIZE + align - 1) & !(align - 1);
+            let front = blk;
+            Block::split_block(front, data - ptr as usize - block::BLOCK_SIZE);
+            blk = (*front).next();
+            (*blk).free = BlockState::InUse;
+            self.free_split(front);
+        }
+
+        // Give back what is left over at the end
+        let size = util::align(size) as usize;
+        if (*blk).size - size >= block::BLOCK_SIZE + mem::size_of::<usize>() {
+            Block::split_block(blk, size);
+            self.free_split((*blk).next());
+        }
+        (*blk).data.add(1).cast()
+    }
+
+    /// Move the data at `ptr` to a block with room for `size` bytes aligned to
+    /// `align` and free the old one.
+    ///
+    /// On failure null is returned and `ptr` is left alone.
+    ///
+    /// # Safety
+    /// `ptr` must have come from this heap and not been freed already.
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+        let new = self.malloc_aligned(size, align);
+        if !new.is_null() {
+            let old = (*Block::get_block(ptr)).size;
+            ptr::copy_nonoverlapping(ptr, new, cmp::min(old, size));
+            self.free(ptr);
+        }
+        new
+    }
+
+    /// `blk` was just split off a block, mark it free and merge it with its
+    /// neighbors so free blocks are never next to each other.
+    unsafe fn free_split(&mut self, blk: *mut Block) {
+        (*blk).free = BlockState::Free;
+        if poison::ENABLED {
+            poison::poison(blk);
+        }
+        let next = (*blk).next();
+        if !next.is_null() && (*next).free == BlockState::Free {
+            Block::absorb(blk);
+        }
+        let prev = (*blk).prev();
+        if !prev.is_null() && (*prev).free == BlockState::Free {
+            Block::absorb(prev);
+        }
+    }
+
+    /// Check the blocks are linked both ways, sit back to back up to the end of
+    /// the heap and that no two free blocks are next to each other.
+    pub fn check(&mut self) -> Result<(), &'static str> {
+        unsafe {
+            let mut prev: *mut Block = ptr::null_mut();
+            let mut end = self.base as usize;
+            for b in self.blocks() {
+                if b as usize != end {
+                    return Err("blocks are not back to back");
+                }
+                if b as usize % mem::align_of::<Block>() != 0 || (*b).size % mem::size_of::<usize>() != 0 {
+                    return Err("block is not word aligned");
+                }
+                if (*b).data != b {
+                    return Err("block does not point at itself");
+                }
+                if (*b).prev() != prev {
+                    return Err("prev link does not match");
+                }
+                if !prev.is_null() && (*prev).free == BlockState::Free && (*b).free == BlockState::Free {
+                    return Err("two free blocks next to each other");
+                }
+                prev = b;
+                end = b as usize + block::BLOCK_SIZE + (*b).size;
+            }
+            if !self.base.is_null() && self.brk.sbrk(0).map(|brk| brk as usize) != Ok(end) {
+                return Err("the last block does not end at the break");
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark `blk` as free, merge it with any free neighbors and give the end of
+    /// the heap back if we can.
+    ///
+    /// # Safety
+    /// `blk` must be a block of this heap that is not already free.
+    pub unsafe fn release(&mut self, mut blk: *mut Block) {
+        (*blk).free = BlockState::Free;
+
+        // Can we combine the previous block with the "current" block
+        if !(*blk).prev().is_null() && (*(*blk).prev()).free == BlockState::Free {
+            blk = Block::absorb((*blk).prev());
+        }
+
+        // Can we combine the next block with "current"
+        if !(*blk).next().is_null() {
+            Block::absorb(blk);
+        }
+        // We may have just swallowed the last block
+        if (*blk).next().is_null() {
+            if !(*blk).prev().is_null() {
+                (*(*blk).prev()).set_next(ptr::null_mut());
+            } else {
+                self.base = ptr::null_mut();
+            }
+            if self.wipes() {
+                wipe::wipe(blk.cast(), block::BLOCK_SIZE + (*blk).size);
+            }
+            // Reset the end of the heap to the last block we have
+            let _ = self.brk.shrink_to(blk as *const u8);
+        }
+    }
+
+    /// Free the data at `ptr` returned by `malloc_block`, it is wiped first if
+    /// the heap wipes.
+    ///
+    /// Poisoned straight away when `poison` is on but never quarantined, the
+    /// quarantine belongs to the global heap.
+    ///
+    /// # Safety
+    /// `ptr` must have come from this heap and not been freed already.
+    pub unsafe fn free(&mut self, ptr: *mut u8) {
+        let blk = Block::get_block(ptr);
+        if self.wipes() {
+            wipe::wipe(ptr, (*blk).size);
+        }
+        if poison::ENABLED {
+            poison::poison(blk);
+        }
+        self.release(blk)
+    }
+}
+
+/// An iterator over the blocks of a `Heap`, see `Heap::blocks`.
+pub struct Blocks {
+    next: *mut Block,
+}
+
+impl Iterator for Blocks {
+    type Item = *mut Block;
+
+    fn next(&mut self) -> Option<*mut Block> {
+        if self.next.is_null() {
+            return None;
+        }
+        let b = self.next;
+        self.next = unsafe { (*b).next() };
+        Some(b)
+    }
+}
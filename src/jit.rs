@@ -0,0 +1,330 @@
+//! Memory for JIT compiled code, it is never writable and executable at once ✍️🏃
+//!
+//! A `CodeBuf` is handed out readable and writable, once the code is written it
+//! is `finalize`d into `Code` which is readable and executable. With
+//! `CodeAllocator::dual_mapped` the pages live in a `MEMFD_CREATE` file, the
+//! writable view is unmapped when the executable view is mapped so the two never
+//! exist at the same time and the executable address never had write
+//! permission.
+//!
+//! Freed code pages are kept and handed out again by later allocations.
+
+use core::{ptr, slice};
+
+use crate::{
+    mmap::{self, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE},
+    syscall,
+    util::page_align,
+};
+
+/// Close the memfd if we ever `EXECVE`.
+const MFD_CLOEXEC: usize = 0x0001;
+/// The number of freed page runs remembered for reuse.
+const FREE_RUNS: usize = 64;
+
+/// Allocates page granular buffers for code.
+pub struct CodeAllocator {
+    /// The memfd both views map when dual mapped.
+    fd: Option<usize>,
+    /// How big the memfd has grown.
+    file_len: usize,
+    /// Runs of pages that have been freed, they can be handed out again.
+    free: [Run; FREE_RUNS],
+    free_len: usize,
+}
+
+/// A run of pages.
+///
+/// `start` is an address when single mapped or an offset into the memfd when
+/// dual mapped.
+#[derive(Clone, Copy, Debug, Default)]
+struct Run {
+    start: usize,
+    len: usize,
+}
+
+/// A writable buffer to put code in, `finalize` it to run the code.
+#[derive(Debug)]
+pub struct CodeBuf {
+    ptr: *mut u8,
+    run: Run,
+}
+
+/// Finalized code, it can be executed but not written.
+#[derive(Debug)]
+pub struct Code {
+    ptr: *const u8,
+    run: Run,
+}
+
+impl CodeBuf {
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.run.len) }
+    }
+
+    /// The length of the buffer, always a whole number of pages.
+    pub fn len(&self) -> usize {
+        self.run.len
+    }
+}
+
+impl Code {
+    /// The start of the code, cast this to the function type you compiled.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.run.len
+    }
+}
+
+impl CodeAllocator {
+    /// Code pages are flipped from writable to executable with `MPROTECT`.
+    pub const fn new() -> Self {
+        Self {
+            fd: None,
+            file_len: 0,
+            free: [Run { start: 0, len: 0 }; FREE_RUNS],
+            free_len: 0,
+        }
+    }
+
+    /// Code pages live in a memfd, the writable and executable views are separate
+    /// mappings that never exist at the same time.
+    pub fn dual_mapped() -> Result<Self, ()> {
+        let fd = unsafe { syscall!(MEMFD_CREATE, b"ralloc-jit\0".as_ptr(), MFD_CLOEXEC) };
+        if syscall::is_err(fd) {
+            return Err(());
+        }
+        Ok(Self {
+            fd: Some(fd),
+            ..Self::new()
+        })
+    }
+
+    /// A writable buffer of at least `size` bytes.
+    pub fn alloc(&mut self, size: usize) -> Result<CodeBuf, ()> {
+        let len = page_align(size.max(1));
+        let run = match self.take_free(len) {
+            Some(run) => run,
+            None => self.grow(len)?,
+        };
+        let ptr = unsafe {
+            match self.fd {
+                Some(fd) => match mmap::map_file(len, PROT_READ | PROT_WRITE, fd, run.start) {
+                    Ok(ptr) => ptr,
+                    Err(()) => {
+                        self.give_back(run);
+                        return Err(());
+                    }
+                },
+                None => {
+                    let ptr = run.start as *mut u8;
+                    if mmap::protect(ptr, len, PROT_READ | PROT_WRITE).is_err() {
+                        self.give_back(run);
+                        return Err(());
+                    }
+                    ptr
+                }
+            }
+        };
+        Ok(CodeBuf { ptr, run })
+    }
+
+    /// Make the code in `buf` executable, it can no longer be written.
+    ///
+    /// On failure the pages of `buf` are released, nothing is left writable.
+    pub fn finalize(&mut self, buf: CodeBuf) -> Result<Code, ()> {
+        let len = buf.run.len;
+        unsafe {
+            let ptr = match self.fd {
+                Some(fd) => {
+                    // Unmap the writable view first so they never coexist. If
+                    // that fails the run is never reused, the view could still
+                    // write to it.
+                    mmap::unmap(buf.ptr, len)?;
+                    match mmap::map_file(len, PROT_READ | PROT_EXEC, fd, buf.run.start) {
+                        Ok(ptr) => ptr,
+                        Err(()) => {
+                            self.give_back(buf.run);
+                            return Err(());
+                        }
+                    }
+                }
+                None => {
+                    if mmap::protect(buf.ptr, len, PROT_READ | PROT_EXEC).is_err() {
+                        let _ = mmap::unmap(buf.ptr, len);
+                        return Err(());
+                    }
+                    buf.ptr
+                }
+            };
+            Ok(Code { ptr, run: buf.run })
+        }
+    }
+
+    /// Free `code`, its pages will be reused.
+    ///
+    /// # Safety
+    /// Nothing may still be executing or pointing into `code`.
+    pub unsafe fn free(&mut self, code: Code) {
+        let len = code.run.len;
+        match self.fd {
+            Some(_) => {
+                let _ = mmap::unmap(code.ptr as *mut u8, len);
+            }
+            None => {
+                let _ = mmap::protect(code.ptr as *mut u8, len, PROT_NONE);
+            }
+        }
+        self.give_back(code.run);
+    }
+
+    /// Find a freed run of at least `len` bytes, any extra stays free.
+    fn take_free(&mut self, len: usize) -> Option<Run> {
+        let idx = self.free[..self.free_len]
+            .iter()
+            .position(|run| run.len >= len)?;
+        let run = self.free[idx];
+        if run.len == len {
+            self.free_len -= 1;
+            self.free[idx] = self.free[self.free_len];
+        } else {
+            self.free[idx] = Run {
+                start: run.start + len,
+                len: run.len - len,
+            };
+        }
+        Some(Run {
+            start: run.start,
+            len,
+        })
+    }
+
+    /// Remember `run` is free, if there is no room to remember it the pages are
+    /// unmapped (single mapped) or leaked in the memfd (dual mapped).
+    ///
+    /// It is merged with the free runs right before and after it so the pages
+    /// can go to a bigger buffer later.
+    fn give_back(&mut self, mut run: Run) {
+        let mut i = 0;
+        while i < self.free_len {
+            let free = self.free[i];
+            if free.start + free.len == run.start || run.start + run.len == free.start {
+                run = Run {
+                    start: free.start.min(run.start),
+                    len: free.len + run.len,
+                };
+                self.free_len -= 1;
+                self.free[i] = self.free[self.free_len];
+            } else {
+                i += 1;
+            }
+        }
+        if self.free_len < FREE_RUNS {
+            self.free[self.free_len] = run;
+            self.free_len += 1;
+        } else if self.fd.is_none() {
+            let _ = unsafe { mmap::unmap(run.start as *mut u8, run.len) };
+        }
+    }
+
+    /// Get `len` more bytes of fresh pages.
+    fn grow(&mut self, len: usize) -> Result<Run, ()> {
+        match self.fd {
+            Some(fd) => {
+                let new_len = self.file_len + len;
+                if syscall::is_err(unsafe { syscall!(FTRUNCATE, fd, new_len) }) {
+                    return Err(());
+                }
+                let run = Run {
+                    start: self.file_len,
+                    len,
+                };
+                self.file_len = new_len;
+                Ok(run)
+            }
+            None => {
+                let ptr = unsafe { mmap::map(ptr::null(), len, PROT_NONE)? };
+                Ok(Run {
+                    start: ptr as usize,
+                    len,
+                })
+            }
+        }
+    }
+}
+
+impl Drop for CodeAllocator {
+    /// Outstanding `Code` stays mapped, only the memfd is closed.
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd {
+            unsafe { syscall!(CLOSE, fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::mem;
+
+    use super::*;
+
+    /// `mov eax, 42; ret`
+    const RET_42: [u8; 6] = [0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3];
+
+    fn run_42(jit: &mut CodeAllocator) {
+        let mut buf = jit.alloc(RET_42.len()).unwrap();
+        buf.as_mut_slice()[..RET_42.len()].copy_from_slice(&RET_42);
+        let code = jit.finalize(buf).unwrap();
+        let f: extern "C" fn() -> i32 = unsafe { mem::transmute(code.as_ptr()) };
+        assert_eq!(f(), 42);
+        unsafe { jit.free(code) };
+    }
+
+    #[test]
+    fn jit_mprotect() {
+        let mut jit = CodeAllocator::new();
+        run_42(&mut jit);
+        // The freed page is reused
+        run_42(&mut jit);
+        assert_eq!(jit.free_len, 1);
+    }
+
+    #[test]
+    fn jit_dual_mapped() {
+        let mut jit = CodeAllocator::dual_mapped().unwrap();
+        run_42(&mut jit);
+        run_42(&mut jit);
+        assert_eq!(jit.file_len, RET_42.len().max(crate::util::PAGE_SIZE));
+    }
+
+    #[test]
+    fn jit_merge_free_runs() {
+        const PAGE: usize = crate::util::PAGE_SIZE;
+        let mut jit = CodeAllocator::dual_mapped().unwrap();
+        let a = jit.alloc(PAGE).unwrap();
+        let b = jit.alloc(PAGE).unwrap();
+        let c = jit.alloc(PAGE).unwrap();
+        // Free the ends then the middle, all three become one run
+        for buf in vec![a, c, b] {
+            let code = jit.finalize(buf).unwrap();
+            unsafe { jit.free(code) };
+        }
+        assert_eq!(jit.free_len, 1);
+        let buf = jit.alloc(3 * PAGE).unwrap();
+        assert_eq!((buf.run.start, jit.file_len), (0, 3 * PAGE));
+    }
+
+    #[test]
+    fn jit_finalize_fails() {
+        let mut jit = CodeAllocator::dual_mapped().unwrap();
+        let buf = jit.alloc(1).unwrap();
+        // The executable view can't be mapped from a closed file
+        let fd = jit.fd.replace(usize::MAX >> 1);
+        assert!(jit.finalize(buf).is_err());
+        jit.fd = fd;
+        assert_eq!(jit.free_len, 1);
+    }
+}
@@ -0,0 +1,222 @@
+//! Who forgot to free what 🕵️
+//!
+//! With the `leak-report` feature a summary of every block that is still in use
+//! is written to stderr when the process exits, `report_leaks` prints the same
+//! thing whenever you like. Nothing here allocates, the report is written with
+//! the raw `WRITE` syscall.
+//!
+//! Leaks are grouped by size and, with the `backtrace` feature, by the stack
+//! they were allocated from. The stacks are raw return addresses, the mappings
+//! of the process are printed after them so they can be resolved offline.
+//!
+//! `check_leaks` does the same for a single closure, only the blocks it
+//! allocated and didn't free are reported, so a test can check itself. A block
+//! `realloc` moves stays in the scope it was first allocated in.
+//!
+//! Sizes are the sizes asked for. Blocks only have room for that and their
+//! scope with the `leak-report` feature, `report_leaks` and `check_leaks` only
+//! exist with it.
+//!
+//! Only the `Block` heap is walked, guarded and fenced allocations are not
+//! counted.
+
+#[cfg(feature = "leak-report")]
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::util::Restore;
+
+#[cfg(feature = "leak-report")]
+use crate::{
+    backtrace,
+    block::{Block, BlockState},
+    report::{RawFd, STDERR},
+};
+
+/// The number of different sizes the report keeps track of, everything else is
+/// counted as "other".
+#[cfg(feature = "leak-report")]
+const GROUPS: usize = 64;
+
+#[cfg(feature = "leak-report")]
+static mut REGISTERED: bool = false;
+
+/// The last `check_leaks` scope handed out, every scope gets its own.
+#[cfg(feature = "leak-report")]
+static LAST_SCOPE: AtomicUsize = AtomicUsize::new(0);
+
+/// The innermost `check_leaks` scope of this thread, zero if there is none.
+#[thread_local]
+static mut SCOPE: usize = 0;
+
+/// Register the exit hook, called when the heap is first made.
+pub fn init() {
+    #[cfg(feature = "leak-report")]
+    unsafe {
+        if !REGISTERED {
+            REGISTERED = true;
+            libc::atexit(at_exit);
+        }
+    }
+}
+
+#[cfg(feature = "leak-report")]
+extern "C" fn at_exit() {
+    report_leaks();
+}
+
+/// Allocations of the same size from the same place.
+#[cfg(feature = "leak-report")]
+#[derive(Clone, Copy, Debug, Default)]
+struct Group {
+    size: usize,
+    /// The index of the allocation stack, see `backtrace::stack`.
+    site: Option<usize>,
+    count: usize,
+}
+
+/// Blocks that are still in use, see `check_leaks`.
+#[cfg(feature = "leak-report")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Leaks {
+    pub blocks: usize,
+    pub bytes: usize,
+}
+
+#[cfg(feature = "leak-report")]
+impl Leaks {
+    pub fn is_empty(&self) -> bool {
+        self.blocks == 0
+    }
+}
+
+/// Write a summary of every block that is still in use to stderr.
+#[cfg(feature = "leak-report")]
+pub fn report_leaks() {
+    report("leak report", |_| true, true);
+}
+
+/// The scope new allocations on this thread belong to, stored in `Block::scope`.
+#[inline]
+pub fn scope() -> usize {
+    unsafe { SCOPE }
+}
+
+/// Run `f` with every allocation it makes on this thread in `scope`.
+pub fn with_scope<R>(scope: usize, f: impl FnOnce() -> R) -> R {
+    let _restore = unsafe { Restore::set(&mut SCOPE, scope) };
+    f()
+}
+
+/// Run `f` and report the blocks it allocated that are still in use after it
+/// returns.
+///
+/// Blocks allocated by other threads in the meantime are not counted. Nothing
+/// is written if there were no leaks.
+#[cfg(feature = "leak-report")]
+pub fn check_leaks(f: impl FnOnce()) -> Leaks {
+    let outer = scope();
+    let inner = LAST_SCOPE.fetch_add(1, Ordering::Relaxed) + 1;
+    with_scope(inner, f);
+
+    let leaks = report("check_leaks", |b| unsafe { (*b).scope() == inner }, false);
+    // What leaked out of us leaked out of the scope around us too
+    if outer != 0 && !leaks.is_empty() {
+        unsafe {
+            for b in crate::HEAP.blocks() {
+                if (*b).scope() == inner {
+                    (*b).set_scope(outer);
+                }
+            }
+        }
+    }
+    leaks
+}
+
+/// Write a summary of the blocks in use that `keep` picks, nothing is written
+/// if there are none unless `always`.
+#[cfg(feature = "leak-report")]
+fn report(title: &str, mut keep: impl FnMut(*mut Block) -> bool, always: bool) -> Leaks {
+    let mut groups = [Group::default(); GROUPS];
+    let mut len = 0;
+    let (mut other_count, mut other_bytes) = (0, 0);
+    let (mut total_count, mut total_bytes) = (0, 0);
+
+    unsafe {
+        for b in crate::HEAP.blocks() {
+            if (*b).free == BlockState::InUse && keep(b) {
+                let size = (*b).requested();
+                let site = backtrace::site(b);
+                total_count += 1;
+                total_bytes += size;
+                match groups[..len]
+                    .iter_mut()
+                    .find(|g| g.size == size && g.site == site)
+                {
+                    Some(g) => g.count += 1,
+                    None if len < GROUPS => {
+                        groups[len] = Group {
+                            size,
+                            site,
+                            count: 1,
+                        };
+                        len += 1;
+                    }
+                    None => {
+                        other_count += 1;
+                        other_bytes += size;
+                    }
+                }
+            }
+        }
+    }
+
+    // Biggest offenders first
+    let groups = &mut groups[..len];
+    groups.sort_unstable_by(|a, b| (b.size * b.count).cmp(&(a.size * a.count)));
+
+    let leaks = Leaks {
+        blocks: total_count,
+        bytes: total_bytes,
+    };
+    if leaks.is_empty() && !always {
+        return leaks;
+    }
+
+    let mut out = RawFd(STDERR);
+    let _ = writeln!(
+        out,
+        "ralloc: {}: {} blocks ({} bytes) still in use",
+        title, total_count, total_bytes
+    );
+    for g in groups.iter() {
+        let _ = writeln!(
+            out,
+            "ralloc:   {:>6} x {:>8} bytes = {:>10} bytes",
+            g.count,
+            g.size,
+            g.count * g.size
+        );
+        if let Some(site) = g.site {
+            let _ = write!(out, "ralloc:       at");
+            for frame in backtrace::stack(site).frames() {
+                let _ = write!(out, " {:#x}", frame);
+            }
+            let _ = writeln!(out);
+        }
+    }
+    if other_count != 0 {
+        let _ = writeln!(
+            out,
+            "ralloc:   {:>6} x    other sizes = {:>10} bytes",
+            other_count, other_bytes
+        );
+    }
+    if backtrace::ENABLED && total_count != 0 {
+        let _ = writeln!(out, "ralloc: mappings:");
+        backtrace::write_maps(STDERR);
+    }
+    leaks
+}
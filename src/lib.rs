@@ -1,11 +1,44 @@
-#![feature(allocator_api, asm, llvm_asm, nonnull_slice_from_raw_parts)]
+#![feature(
+    allocator_api,
+    asm,
+    llvm_asm,
+    naked_functions,
+    nonnull_slice_from_raw_parts,
+    thread_local
+)]
 #![allow(unused)]
+mod backtrace;
 mod block;
 mod breaks;
+mod cgroup;
+mod count;
+mod efence;
+mod fail;
+mod guarded;
+mod heap;
+mod jit;
+mod leak;
+mod limit;
 mod mmap;
+#[cfg(test)]
+mod model;
+mod oom;
 mod pointer;
+mod poison;
+mod profile;
+mod random;
+mod redzone;
+mod report;
 mod sc;
+mod secret;
+mod signal;
+#[cfg(any(test, feature = "sim"))]
+mod sim;
+mod stats;
+mod tag;
+mod trace;
 mod util;
+mod wipe;
 
 use core::{
     alloc::{AllocError, AllocRef, GlobalAlloc, Layout, LayoutErr},
@@ -14,9 +47,31 @@ use core::{
 };
 
 use block::{Block, BlockState};
-use breaks::{brk, sbrk};
+#[cfg(feature = "sim")]
+pub use breaks::Break;
+use breaks::ProgramBreak;
+pub use cgroup::{cgroup_limits, CgroupLimits};
+pub use count::{assert_no_alloc, count_allocs, AllocCounts};
+pub use fail::{injected_failures, set_fail_policy, FailPolicy};
+pub use guarded::set_guard_sample_rate;
+#[cfg(feature = "sim")]
+pub use heap::Heap;
+pub use jit::{Code, CodeAllocator, CodeBuf};
+#[cfg(feature = "leak-report")]
+pub use leak::{check_leaks, report_leaks, Leaks};
+pub use limit::{set_heap_limit, set_limit_handler, set_tag_limit, Limit, LimitHandler};
+pub use oom::{set_oom_policy, OomHandler, OomPolicy};
+pub use poison::set_quarantine_size;
+pub use profile::{dump_heap_profile, set_profile_sample_interval};
 use sc as syscall;
-use util::{align, MIN_ALIGN};
+pub use secret::SecretHeap;
+#[cfg(feature = "sim")]
+pub use sim::SimBreak;
+pub use stats::{stats, Stats};
+pub use tag::{tag_stats, with_tag, TagStats, TAGS};
+pub use trace::flush_trace;
+use util::align;
+pub use wipe::set_wipe_on_free;
 
 macro_rules! dbg {
     ($val:expr) => {
@@ -38,32 +93,60 @@ macro_rules! dbg {
     };
 }
 
-static mut GLOBAL_BASE: *mut Block = ptr::null_mut();
+static mut HEAP: heap::Heap<ProgramBreak> = heap::Heap::new(ProgramBreak);
+
+/// Was `ptr` handed out from our heap of `Block`s rather than by one of the page
+/// based allocators.
+fn in_heap(ptr: *const u8) -> bool {
+    !(efence::enabled() || guarded::ENABLED && guarded::contains(ptr) || oom::in_reserve(ptr))
+}
 
 ///
 /// # Safety
 /// It ain't but I'm working on it.
 unsafe fn free(ptr: *mut u8, layout: Layout) {
-    let mut blk = Block::get_block(ptr);
-    (*blk).free = BlockState::Free;
-
-    // Can we combine the previous block with the "current" block
-    if !(*blk).prev.is_null() && (*(*blk).prev).free == BlockState::Free {
-        blk = Block::absorb((*blk).prev);
+    count::on_dealloc(layout.size());
+    if oom::in_reserve(ptr) {
+        return oom::reserve_free(ptr);
+    }
+    if efence::enabled() {
+        let tag = efence::tag(ptr, layout);
+        efence::free(ptr, layout);
+        return tag::on_free(tag, layout.size());
+    }
+    if guarded::ENABLED && guarded::contains(ptr) {
+        let tag = guarded::tag(ptr);
+        guarded::free(ptr, layout);
+        return tag::on_free(tag, layout.size());
     }
+    redzone::verify(ptr, layout, "dealloc");
 
-    // Can we combine the next block with "current"
-    if !(*blk).next.is_null() {
-        Block::absorb(blk);
-    } else {
-        if !(*blk).prev.is_null() {
-            (*(*blk).prev).next = ptr::null_mut();
-        } else {
-            dbg!(&(*GLOBAL_BASE));
-            GLOBAL_BASE = ptr::null_mut();
+    let blk = Block::get_block(ptr);
+    tag::on_free((*blk).tag, layout.size());
+    if backtrace::ENABLED {
+        backtrace::forget(blk);
+    }
+    if profile::ENABLED {
+        profile::on_free(blk);
+    }
+    if wipe::enabled() {
+        wipe::wipe(ptr, (*blk).size);
+    }
+    if poison::ENABLED {
+        poison::poison(blk);
+        poison::quarantine(blk);
+        loop {
+            let old = poison::evict();
+            if old.is_null() {
+                break;
+            }
+            HEAP.release(old);
         }
-        // Reset the end of the heap to the last block we have
-        brk(blk as *const u8);
+    } else {
+        HEAP.release(blk);
+    }
+    if cgroup::ENABLED {
+        cgroup::on_free(layout.size());
     }
 }
 
@@ -75,42 +158,66 @@ unsafe fn free(ptr: *mut u8, layout: Layout) {
 /// if and aligned pointer is needed you must do it again.
 /// FIXME the above should be encapsulated.
 unsafe fn malloc(layout: Layout) -> *mut u8 {
-    let size = layout.size();
-    // This is our first alloc
-    if GLOBAL_BASE.is_null() {
-        let blk = Block::extend_heap(ptr::null_mut(), size);
-        GLOBAL_BASE = blk;
-        dbg!(*GLOBAL_BASE);
-
-        (*blk).data.add(1) as *mut u8
-    } else {
-        dbg!(*GLOBAL_BASE);
+    count::check(layout);
+    let ptr = allocate(layout);
+    if !ptr.is_null() {
+        count::on_alloc(layout);
+    }
+    ptr
+}
 
-        // watch this when fixing ptr arithmetic this size is the data size not total
-        let blk_ptr = Block::find_block(GLOBAL_BASE, size);
-        if blk_ptr.is_null() {
-            panic!("Found null pointer")
+/// Where `malloc` gets the memory from, nothing is counted.
+unsafe fn allocate(layout: Layout) -> *mut u8 {
+    if fail::ENABLED && fail::should_fail(layout.size()) {
+        return ptr::null_mut();
+    }
+    let tag = tag::current();
+    if !limit::admit(tag, layout.size()) {
+        return ptr::null_mut();
+    }
+    if efence::enabled() {
+        let ptr = efence::alloc(layout, tag);
+        if !ptr.is_null() {
+            tag::on_alloc(tag, layout.size());
         }
-
-        // dbg!(*blk_ptr);
-        // dbg!(size);
-
-        // PTR MATH fix
-        let blk_size = (*blk_ptr).size;
-        if ((blk_size as isize - size as isize) >= (block::BLOCK_SIZE + 4) as isize) {
-            Block::split_block(blk_ptr, size);
+        return ptr;
+    }
+    if guarded::ENABLED {
+        let ptr = guarded::alloc(layout, tag);
+        if !ptr.is_null() {
+            tag::on_alloc(tag, layout.size());
+            return ptr;
         }
+    }
 
-        // We need to extend the heap
-        if (*blk_ptr).free == BlockState::InUse {
-            let new = Block::extend_heap(blk_ptr, size);
-            return (*new).data.add(1) as *mut u8;
+    leak::init();
+    cgroup::init();
+    let size = layout.size() + redzone::padding();
+    let mut ptr = HEAP.malloc_aligned(size, layout.align());
+    if ptr.is_null() {
+        ptr = oom::out_of_memory(layout, || HEAP.malloc_aligned(size, layout.align()));
+        // Reserve allocations have no header to fill in
+        if oom::in_reserve(ptr) {
+            return ptr;
         }
-
-        (*blk_ptr).free = BlockState::InUse;
-        dbg!(*GLOBAL_BASE);
-        (*blk_ptr).data.add(1) as *mut u8
     }
+    if !ptr.is_null() {
+        let blk = Block::get_block(ptr);
+        (*blk).set_scope(leak::scope());
+        (*blk).set_requested(layout.size());
+        (*blk).tag = tag;
+        tag::on_alloc(tag, layout.size());
+    }
+    if redzone::ENABLED && !ptr.is_null() {
+        redzone::paint(ptr, layout.size());
+    }
+    if backtrace::ENABLED && !ptr.is_null() {
+        backtrace::record(Block::get_block(ptr));
+    }
+    if profile::ENABLED && !ptr.is_null() {
+        profile::on_alloc(Block::get_block(ptr));
+    }
+    ptr
 }
 
 // TODO
@@ -126,12 +233,27 @@ unsafe fn align_malloc(layout: Layout) -> *mut u8 {
 }
 
 unsafe fn realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-    eprintln!("REALLOC {:?} {:?}", ptr, layout);
+    if in_heap(ptr) {
+        redzone::verify(ptr, layout, "realloc");
+    }
+
     // SAFETY: the caller must ensure that the `new_size` does not overflow.
     // `layout.align()` comes from a `Layout` and is thus guaranteed to be valid.
     let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
     // SAFETY: the caller must ensure that `new_layout` is greater than zero.
-    let new_ptr = malloc(new_layout);
+    let new_ptr = if in_heap(ptr) {
+        // The data keeps its tag and leak scope wherever it moves
+        let blk = Block::get_block(ptr);
+        tag::with_tag((*blk).tag, || leak::with_scope((*blk).scope(), || malloc(new_layout)))
+    } else if oom::in_reserve(ptr) {
+        malloc(new_layout)
+    } else if efence::enabled() {
+        tag::with_tag(efence::tag(ptr, layout), || malloc(new_layout))
+    } else if guarded::ENABLED && guarded::contains(ptr) {
+        tag::with_tag(guarded::tag(ptr), || malloc(new_layout))
+    } else {
+        malloc(new_layout)
+    };
 
     if !new_ptr.is_null() {
         // SAFETY: the previously allocated block cannot overlap the newly allocated block.
@@ -140,8 +262,6 @@ unsafe fn realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
 
         free(ptr, layout);
     }
-    dbg!(*GLOBAL_BASE);
-    dbg!(*Block::get_block(new_ptr));
     new_ptr
 }
 
@@ -149,55 +269,54 @@ pub struct Ralloc;
 
 unsafe impl GlobalAlloc for Ralloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // dbg!(&layout);
-        eprintln!("ALLOC {}", layout.size());
-        if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() {
-            malloc(layout)
-        } else {
-            // ALIGN we have a large enough value to
-            dbg!(malloc(layout))
+        let ptr = malloc(layout);
+        if trace::ENABLED {
+            trace::record(trace::Op::Alloc, ptr, ptr::null(), layout.size(), layout.align());
         }
+        ptr
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        eprintln!("ALLOC ZERO {}", layout.size());
-        let ptr = self.alloc(layout);
+        let ptr = malloc(layout);
         if !ptr.is_null() {
             ptr::write_bytes(ptr, 0, layout.size());
         }
+        if trace::ENABLED {
+            trace::record(trace::Op::AllocZeroed, ptr, ptr::null(), layout.size(), layout.align());
+        }
         ptr
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        eprintln!("REALLOC {}", layout.size());
-        realloc(ptr, layout, new_size)
+        let new_ptr = realloc(ptr, layout, new_size);
+        if trace::ENABLED {
+            trace::record(trace::Op::Realloc, new_ptr, ptr, new_size, layout.align());
+        }
+        new_ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        eprintln!("DEALLOC {:?} {}", ptr, layout.size());
+        if trace::ENABLED {
+            trace::record(trace::Op::Dealloc, ptr, ptr::null(), layout.size(), layout.align());
+        }
         free(ptr, layout)
     }
 }
 
 unsafe impl AllocRef for &Ralloc {
     fn alloc(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        Ok(unsafe {
-            let ptr = NonNull::new_unchecked(malloc(layout));
-            NonNull::slice_from_raw_parts(ptr, layout.size())
-        })
+        let ptr = NonNull::new(unsafe { malloc(layout) }).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
 
     fn alloc_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        println!("{:?}", layout);
-        Ok(unsafe {
-            let ptr = NonNull::new_unchecked(malloc(layout));
-            NonNull::slice_from_raw_parts(ptr, layout.size())
-        })
+        let ptr = NonNull::new(unsafe { malloc(layout) }).ok_or(AllocError)?;
+        unsafe { ptr::write_bytes(ptr.as_ptr(), 0, layout.size()) };
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
 
     unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
-        println!("{:?}", layout);
-        panic!("its a start")
+        free(ptr.as_ptr(), layout)
     }
 
     unsafe fn grow(
@@ -206,15 +325,32 @@ unsafe impl AllocRef for &Ralloc {
         old_layout: Layout,
         new_layout: Layout,
     ) -> Result<NonNull<[u8]>, AllocError> {
-        panic!()
+        let new = if old_layout.align() == new_layout.align() {
+            realloc(ptr.as_ptr(), old_layout, new_layout.size())
+        } else {
+            // `realloc` keeps the old alignment, move it ourselves
+            let new = malloc(new_layout);
+            if !new.is_null() {
+                ptr::copy_nonoverlapping(ptr.as_ptr(), new, old_layout.size());
+                free(ptr.as_ptr(), old_layout);
+            }
+            new
+        };
+        // On failure `ptr` is still allocated and untouched
+        let new = NonNull::new(new).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(new, new_layout.size()))
     }
+
     unsafe fn grow_zeroed(
         &self,
         ptr: NonNull<u8>,
         old_layout: Layout,
         new_layout: Layout,
     ) -> Result<NonNull<[u8]>, AllocError> {
-        panic!()
+        let new = self.grow(ptr, old_layout, new_layout)?;
+        let start = (new.as_ptr() as *mut u8).add(old_layout.size());
+        ptr::write_bytes(start, 0, new_layout.size() - old_layout.size());
+        Ok(new)
     }
 }
 
@@ -223,12 +359,12 @@ mod tests {
     use super::*;
     #[test]
     fn it_works() {
+        // Our own heap so the global one is left alone
+        let mut heap = heap::Heap::new(sim::SimBreak::new(4096));
         unsafe {
-            println!("ONE MALLOC {:?}", malloc(Layout::new::<usize>()));
-            println!("{:#?}", (*GLOBAL_BASE));
-
-            println!("TWO MALLOC {:?}", malloc(Layout::new::<u32>()));
-            println!("{:?}", (*GLOBAL_BASE));
+            let one = heap.malloc_block(mem::size_of::<usize>());
+            let two = heap.malloc_block(mem::size_of::<u32>());
+            assert!(!one.is_null() && !two.is_null() && one != two);
         }
     }
 }
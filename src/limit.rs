@@ -0,0 +1,150 @@
+//! Budgets 🧱 for how much memory can be live at once.
+//!
+//! The whole heap and each `with_tag` tag can be given a limit on the bytes they
+//! have live. An allocation that would go over fails, `alloc` returns null and
+//! `AllocRef` returns `AllocError`, so a cache or a request handler can be
+//! capped long before the kernel's OOM killer gets involved.
+//!
+//! A handler set with `set_limit_handler` is called before the allocation
+//! fails. If it frees something and returns `true` the limit is checked once
+//! more. Allocations the handler makes itself go over without calling it again.
+//!
+//! Limits count the sizes asked for, like `tag_stats`, wherever the memory comes
+//! from. A `realloc` needs room for the old and the new size while it copies.
+
+use crate::{
+    tag::{self, TAGS},
+    util::Restore,
+};
+
+/// No limit.
+const UNLIMITED: usize = usize::MAX;
+
+static mut LIMITS: Limits = Limits {
+    heap: UNLIMITED,
+    tags: [UNLIMITED; TAGS],
+    handler: None,
+};
+
+/// Is this thread inside the limit handler.
+#[thread_local]
+static mut IN_HANDLER: bool = false;
+
+/// The limit an allocation would go over.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    /// The limit on the whole heap.
+    Heap,
+    /// The limit on one tag.
+    Tag(u8),
+}
+
+/// Called with the limit that was hit and the size of the allocation, returns
+/// `true` if it made room and the allocation should be tried again.
+pub type LimitHandler = fn(Limit, usize) -> bool;
+
+struct Limits {
+    heap: usize,
+    tags: [usize; TAGS],
+    handler: Option<LimitHandler>,
+}
+
+/// Fail allocations that would take the live bytes of the heap over `bytes`,
+/// `None` takes the limit away.
+pub fn set_heap_limit(bytes: Option<usize>) {
+    unsafe { LIMITS.heap = bytes.unwrap_or(UNLIMITED) };
+}
+
+/// Fail allocations tagged `tag` that would take the tag's live bytes over
+/// `bytes`, `None` takes the limit away.
+pub fn set_tag_limit(tag: u8, bytes: Option<usize>) {
+    unsafe { LIMITS.tags[tag as usize] = bytes.unwrap_or(UNLIMITED) };
+}
+
+/// Call `handler` before an allocation fails because of a limit, `None` fails
+/// straight away.
+pub fn set_limit_handler(handler: Option<LimitHandler>) {
+    unsafe { LIMITS.handler = handler };
+}
+
+/// The limit an allocation of `size` bytes under `tag` would go over.
+fn exceeded(tag: u8, size: usize) -> Option<Limit> {
+    let limits = unsafe { &LIMITS };
+    if tag::total_live_bytes().saturating_add(size) > limits.heap {
+        Some(Limit::Heap)
+    } else if tag::live_bytes(tag).saturating_add(size) > limits.tags[tag as usize] {
+        Some(Limit::Tag(tag))
+    } else {
+        None
+    }
+}
+
+/// Can `size` more bytes be allocated under `tag`, asks the handler for room
+/// if they can't.
+pub fn admit(tag: u8, size: usize) -> bool {
+    admit_with(tag, size, unsafe { LIMITS.handler })
+}
+
+fn admit_with(tag: u8, size: usize, handler: Option<LimitHandler>) -> bool {
+    let limit = match exceeded(tag, size) {
+        None => return true,
+        Some(limit) => limit,
+    };
+    unsafe {
+        // The handler may need memory to make room
+        if IN_HANDLER {
+            return true;
+        }
+        let handler = match handler {
+            Some(handler) => handler,
+            None => return false,
+        };
+        let retry = {
+            let _in_handler = Restore::set(&mut IN_HANDLER, true);
+            handler(limit, size)
+        };
+        retry && exceeded(tag, size).is_none()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tag_limit() {
+        // Only this test uses the tag and the handler is passed in, other
+        // tests can run alongside
+        const TAG: u8 = 220;
+        set_tag_limit(TAG, Some(100));
+        tag::on_alloc(TAG, 60);
+        assert!(admit(TAG, 40));
+        assert!(!admit(TAG, 41));
+        assert!(!admit_with(TAG, 41, Some(|_, _| false)));
+
+        // The handler frees enough to fit, allocating on the way
+        fn handler(limit: Limit, size: usize) -> bool {
+            assert_eq!((limit, size), (Limit::Tag(TAG), 41));
+            assert!(admit_with(TAG, 1000, Some(handler)));
+            tag::on_free(TAG, 60);
+            true
+        }
+        assert!(admit_with(TAG, 41, Some(handler)));
+        assert!(!unsafe { IN_HANDLER });
+
+        set_tag_limit(TAG, None);
+        assert!(admit(TAG, usize::MAX));
+    }
+
+    #[test]
+    fn panicking_handler() {
+        const TAG: u8 = 221;
+        set_tag_limit(TAG, Some(0));
+        let panicked = std::panic::catch_unwind(|| admit_with(TAG, 1, Some(|_, _| panic!())));
+        assert!(panicked.is_err());
+        // Later allocations are held to the limit again
+        assert!(!unsafe { IN_HANDLER });
+        assert!(!admit_with(TAG, 1, None));
+        set_tag_limit(TAG, None);
+    }
+}
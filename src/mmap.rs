@@ -2,15 +2,28 @@
 
 use core::ptr;
 
-use crate::syscall;
+use crate::{
+    stats::{self, Syscall},
+    syscall,
+};
 
 use libc::PT_DYNAMIC;
 
+/// The memory can not be touched at all.
+pub const PROT_NONE: u8 = 0;
 /// This memory can be read.
 /// Sets the permissions to allow reading.
-const PROT_READ: u8 = 1;
+pub const PROT_READ: u8 = 1;
 /// Sets the permissions so the memory can be written to.
-const PROT_WRITE: u8 = 2;
+pub const PROT_WRITE: u8 = 2;
+/// The memory can be executed as code.
+pub const PROT_EXEC: u8 = 4;
+/// Don't reserve swap for this memory, it is only address space until it's touched.
+pub const MAP_NORESERVE: usize = 0x4000;
+/// The kernel can throw away the contents of these pages, they read as zero after.
+pub const MADV_DONTNEED: usize = 4;
+/// Leave these pages out of any core dump.
+pub const MADV_DONTDUMP: usize = 16;
 /// Share this memory with all other processes. Changes will
 /// be written back to memory from other procs.
 const MAP_SHARED: u8 = 0x0001;
@@ -84,6 +97,7 @@ fn current_page() -> *const u8 {
 
 // #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 pub unsafe fn _mmap(ptr: *const u8, size: usize) -> *const u8 {
+    stats::count(Syscall::Mmap);
     syscall!(
         MMAP,
         ptr,
@@ -95,6 +109,108 @@ pub unsafe fn _mmap(ptr: *const u8, size: usize) -> *const u8 {
     ) as *const u8
 }
 
+/// Map `size` bytes of private anonymous memory with the protection `prot`.
+///
+/// The kernel is free to ignore `hint`, pass null if you don't care where the
+/// memory ends up.
+pub unsafe fn map(hint: *const u8, size: usize, prot: u8) -> Result<*mut u8, ()> {
+    stats::count(Syscall::Mmap);
+    let ptr = syscall!(
+        MMAP,
+        hint,
+        size,
+        prot,
+        MAP_PRIVATE | MAP_ANON,
+        NOT_FILE,
+        OFFSET
+    );
+    if syscall::is_err(ptr) {
+        Err(())
+    } else {
+        Ok(ptr as *mut u8)
+    }
+}
+
+/// Map `size` bytes of the file `fd` starting at `offset`, writes are shared with
+/// every other mapping of the file.
+pub unsafe fn map_file(size: usize, prot: u8, fd: usize, offset: usize) -> Result<*mut u8, ()> {
+    stats::count(Syscall::Mmap);
+    let ptr = syscall!(MMAP, ptr::null::<u8>(), size, prot, MAP_SHARED, fd, offset);
+    if syscall::is_err(ptr) {
+        Err(())
+    } else {
+        Ok(ptr as *mut u8)
+    }
+}
+
+/// Reserve `size` bytes of address space that can't be touched until parts of
+/// it are made accessible with `protect`.
+pub unsafe fn reserve(hint: *const u8, size: usize) -> Result<*mut u8, ()> {
+    stats::count(Syscall::Mmap);
+    let ptr = syscall!(
+        MMAP,
+        hint,
+        size,
+        PROT_NONE,
+        (MAP_PRIVATE | MAP_ANON) as usize | MAP_NORESERVE,
+        NOT_FILE,
+        OFFSET
+    );
+    if syscall::is_err(ptr) {
+        Err(())
+    } else {
+        Ok(ptr as *mut u8)
+    }
+}
+
+/// Change the protection of the pages covering `ptr..ptr + size` to `prot`.
+pub unsafe fn protect(ptr: *mut u8, size: usize, prot: u8) -> Result<(), ()> {
+    stats::count(Syscall::Mprotect);
+    if syscall::is_err(syscall!(MPROTECT, ptr, size, prot)) {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+/// Give the pages covering `ptr..ptr + size` back to the kernel.
+pub unsafe fn unmap(ptr: *mut u8, size: usize) -> Result<(), ()> {
+    stats::count(Syscall::Munmap);
+    if syscall::is_err(syscall!(MUNMAP, ptr, size)) {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+/// Give the kernel `advice` about the pages covering `ptr..ptr + size`.
+pub unsafe fn advise(ptr: *mut u8, size: usize, advice: usize) -> Result<(), ()> {
+    stats::count(Syscall::Madvise);
+    if syscall::is_err(syscall!(MADVISE, ptr, size, advice)) {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+/// Keep the pages covering `ptr..ptr + size` in RAM, they will never be swapped.
+pub unsafe fn lock(ptr: *mut u8, size: usize) -> Result<(), ()> {
+    if syscall::is_err(syscall!(MLOCK, ptr, size)) {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+/// Allow the pages covering `ptr..ptr + size` to be swapped again.
+pub unsafe fn unlock(ptr: *mut u8, size: usize) -> Result<(), ()> {
+    if syscall::is_err(syscall!(MUNLOCK, ptr, size)) {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
 #[test]
 fn mmap_call() {
     unsafe {
@@ -0,0 +1,215 @@
+//! Model based testing 🎲 of a `Heap`.
+//!
+//! Random sequences of allocs, reallocs and frees run against a heap on a
+//! `SimBreak` while a model keeps track of what should be live. After every step
+//! each live region must be aligned, must not overlap any other and must still
+//! hold the bytes written to it, and `Heap::check` must pass. A failing sequence
+//! is shrunk to a small one before it is reported.
+
+use core::{fmt, ptr};
+
+use crate::{heap::Heap, random::Rng, sim::SimBreak};
+
+/// Big enough for the largest sequence we generate.
+const HEAP_SIZE: usize = 16 << 20;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    Alloc { size: usize, align: usize },
+    /// Resize live region `idx` modulo the number of live regions.
+    Realloc { idx: usize, size: usize },
+    /// Free live region `idx` modulo the number of live regions.
+    Free { idx: usize },
+}
+
+/// What the model knows about a live allocation.
+struct Region {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+    /// Every byte of the region holds this.
+    byte: u8,
+}
+
+impl fmt::Debug for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}..+{} (align {})", self.ptr, self.size, self.align)
+    }
+}
+
+fn size(rng: &mut Rng) -> usize {
+    match rng.below(10) {
+        0 => rng.below(16 * 1024) + 1,
+        _ => rng.below(256) + 1,
+    }
+}
+
+fn generate(rng: &mut Rng, len: usize) -> Vec<Op> {
+    (0..len)
+        .map(|_| match rng.below(10) {
+            0..=4 => Op::Alloc {
+                size: size(rng),
+                align: 1 << rng.below(13),
+            },
+            5..=6 => Op::Realloc {
+                idx: rng.below(usize::MAX),
+                size: size(rng),
+            },
+            _ => Op::Free {
+                idx: rng.below(usize::MAX),
+            },
+        })
+        .collect()
+}
+
+/// Do `op` to the heap and the model, reallocs check the data came along.
+unsafe fn apply(heap: &mut Heap<SimBreak>, live: &mut Vec<Region>, op: Op, byte: u8) -> Result<(), String> {
+    match op {
+        Op::Alloc { size, align } => {
+            let ptr = heap.malloc_aligned(size, align);
+            ptr::write_bytes(ptr, byte, size);
+            live.push(Region { ptr, size, align, byte });
+        }
+        Op::Realloc { idx, size } if !live.is_empty() => {
+            let len = live.len();
+            let r = &mut live[idx % len];
+            let ptr = heap.realloc(r.ptr, size, r.align);
+            let kept = r.size.min(size);
+            if let Some(i) = (0..kept).find(|i| *ptr.add(*i) != r.byte) {
+                return Err(format!("realloc of {:?} lost byte {}", r, i));
+            }
+            ptr::write_bytes(ptr, r.byte, size);
+            r.ptr = ptr;
+            r.size = size;
+        }
+        Op::Free { idx } if !live.is_empty() => {
+            let r = live.swap_remove(idx % live.len());
+            heap.free(r.ptr);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Every live region is aligned, whole and on its own.
+unsafe fn check(heap: &mut Heap<SimBreak>, live: &mut Vec<Region>) -> Result<(), String> {
+    heap.check()?;
+    for r in live.iter() {
+        if r.ptr as usize % r.align != 0 {
+            return Err(format!("{:?} is not aligned", r));
+        }
+        if let Some(i) = (0..r.size).find(|i| *r.ptr.add(*i) != r.byte) {
+            return Err(format!("{:?} was overwritten at byte {}", r, i));
+        }
+    }
+    live.sort_by_key(|r| r.ptr);
+    for w in live.windows(2) {
+        if w[0].ptr as usize + w[0].size > w[1].ptr as usize {
+            return Err(format!("{:?} overlaps {:?}", w[0], w[1]));
+        }
+    }
+    Ok(())
+}
+
+/// Run `ops` against a fresh heap, the error says which step failed and why.
+fn run(ops: &[Op]) -> Result<(), String> {
+    let mut heap = Heap::new(SimBreak::new(HEAP_SIZE));
+    let mut live = Vec::new();
+    unsafe {
+        for (step, op) in ops.iter().enumerate() {
+            apply(&mut heap, &mut live, *op, step as u8 | 1)
+                .and_then(|_| check(&mut heap, &mut live))
+                .map_err(|e| format!("step {} {:?}: {}", step, op, e))?;
+        }
+        for r in live.drain(..) {
+            heap.free(r.ptr);
+        }
+    }
+    if !heap.base.is_null() || heap.brk().used() != 0 {
+        return Err("the heap is not empty after freeing everything".into());
+    }
+    Ok(())
+}
+
+/// Smaller versions of `op` to try while shrinking.
+fn smaller(op: Op) -> Vec<Op> {
+    let mut out = Vec::new();
+    match op {
+        Op::Alloc { size, align } => {
+            if size > 1 {
+                out.push(Op::Alloc { size: size / 2, align });
+            }
+            if align > 1 {
+                out.push(Op::Alloc { size, align: align / 2 });
+            }
+        }
+        Op::Realloc { idx, size } if size > 1 => out.push(Op::Realloc { idx, size: size / 2 }),
+        _ => {}
+    }
+    out
+}
+
+/// Make a sequence that `fails` smaller while it keeps failing, first by
+/// dropping runs of ops then by making sizes and alignments smaller.
+fn shrink(mut ops: Vec<Op>, fails: impl Fn(&[Op]) -> bool) -> Vec<Op> {
+    let mut chunk = ops.len() / 2;
+    while chunk > 0 {
+        let mut i = 0;
+        while i + chunk <= ops.len() {
+            let mut fewer = ops.clone();
+            fewer.drain(i..i + chunk);
+            if fails(&fewer) {
+                ops = fewer;
+            } else {
+                i += chunk;
+            }
+        }
+        chunk /= 2;
+    }
+
+    for i in 0..ops.len() {
+        while let Some(op) = smaller(ops[i]).into_iter().find(|op| {
+            let mut tried = ops.clone();
+            tried[i] = *op;
+            fails(&tried)
+        }) {
+            ops[i] = op;
+        }
+    }
+    ops
+}
+
+#[test]
+fn heap_model() {
+    for seed in 1..=32 {
+        let ops = generate(&mut Rng::new(seed), 200);
+        if run(&ops).is_err() {
+            let ops = shrink(ops, |ops| run(ops).is_err());
+            panic!(
+                "seed {} failed: {}\nshrunk to {:#?}",
+                seed,
+                run(&ops).unwrap_err(),
+                ops
+            );
+        }
+    }
+}
+
+#[test]
+fn shrink_to_smallest() {
+    // Pretend anything that allocates more than 1000 bytes at once is a bug
+    let fails = |ops: &[Op]| {
+        ops.iter()
+            .any(|op| matches!(op, Op::Alloc { size, .. } if *size > 1000))
+    };
+    let mut rng = Rng::new(7);
+    let ops = loop {
+        let ops = generate(&mut rng, 200);
+        if fails(&ops) {
+            break ops;
+        }
+    };
+    let ops = shrink(ops, fails);
+    assert_eq!(ops.len(), 1);
+    assert!(matches!(ops[0], Op::Alloc { size, align: 1 } if size > 1000 && size <= 2000));
+}
@@ -0,0 +1,215 @@
+//! What to do when the memory runs out 🪫
+//!
+//! When the heap can't grow any more `set_oom_policy` decides what happens, the
+//! default is to return null like any other allocator. A `Retry` handler gets
+//! the chance to drop caches and have the allocation tried again, `Abort` writes
+//! what was asked for and how big the heap is to stderr and aborts.
+//!
+//! A small reserve is kept aside for the code that deals with running out.
+//! Small allocations the heap can't serve while the `Retry` handler runs or
+//! while a panic unwinds come from it, so the handler can still do its work and
+//! the panic message can still be formatted. Everything else gets the policy,
+//! `Null` returns null. The `Abort` diagnostic doesn't allocate. The reserve is
+//! only reused once everything in it has been freed.
+//!
+//! Reserve allocations have no `Block` header, they are not tagged, profiled or
+//! checked for leaks and their redzones aren't checked.
+//!
+//! Running into the cgroup ceiling is running out of memory. Failures from
+//! `set_fail_policy` and the limits are not, they just return null.
+
+use core::{alloc::Layout, ptr};
+
+use crate::{report, stats, util::Restore};
+
+/// The bytes set aside for when the heap runs out.
+pub const RESERVE_SIZE: usize = 64 * 1024;
+/// Bigger allocations never come from the reserve.
+const RESERVE_MAX_ALLOC: usize = 4096;
+/// How many times a `Retry` handler can ask for another try.
+const RETRIES: usize = 4;
+
+static mut POLICY: OomPolicy = OomPolicy::Null;
+
+static mut RESERVE: Reserve = Reserve {
+    mem: [0; RESERVE_SIZE],
+    used: 0,
+    live: 0,
+};
+
+/// Is this thread inside the `Retry` handler, it may use the reserve.
+#[thread_local]
+static mut IN_HANDLER: bool = false;
+
+/// Called with the allocation that didn't fit, returns `true` if it freed some
+/// memory and the allocation should be tried again.
+pub type OomHandler = fn(Layout) -> bool;
+
+/// What happens when the heap has no room for an allocation, see `set_oom_policy`.
+#[derive(Clone, Copy, Debug)]
+pub enum OomPolicy {
+    /// Return null, or `AllocError` through `AllocRef`.
+    Null,
+    /// Call the handler and try again while it returns `true`, up to a few
+    /// times, then return null. Allocations the handler makes don't call it again.
+    Retry(OomHandler),
+    /// Write a diagnostic to stderr and abort.
+    Abort,
+}
+
+#[repr(C, align(4096))]
+struct Reserve {
+    mem: [u8; RESERVE_SIZE],
+    /// Everything below this has been handed out.
+    used: usize,
+    /// The allocations handed out and not freed yet.
+    live: usize,
+}
+
+/// Decide what happens when the heap runs out of memory.
+pub fn set_oom_policy(policy: OomPolicy) {
+    unsafe { POLICY = policy };
+}
+
+/// The heap had no room for `layout`, follow the policy. `retry` tries the
+/// heap again.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn out_of_memory(layout: Layout, mut retry: impl FnMut() -> *mut u8) -> *mut u8 {
+    // Dealing with running out, not running out again
+    if IN_HANDLER || std::thread::panicking() {
+        return reserve_alloc(layout);
+    }
+    match POLICY {
+        OomPolicy::Null => ptr::null_mut(),
+        OomPolicy::Retry(handler) => {
+            let _in_handler = Restore::set(&mut IN_HANDLER, true);
+            let mut ptr = ptr::null_mut();
+            for _ in 0..RETRIES {
+                if !handler(layout) {
+                    break;
+                }
+                ptr = retry();
+                if !ptr.is_null() {
+                    break;
+                }
+            }
+            ptr
+        }
+        OomPolicy::Abort => report::abort(format_args!(
+            "out of memory allocating {} bytes (align {}), the heap is {} bytes",
+            layout.size(),
+            layout.align(),
+            stats::heap_size()
+        )),
+    }
+}
+
+/// Did `ptr` come from the reserve.
+pub fn in_reserve(ptr: *const u8) -> bool {
+    let start = unsafe { RESERVE.mem.as_ptr() } as usize;
+    (start..start + RESERVE_SIZE).contains(&(ptr as usize))
+}
+
+/// Hand out `layout` from the reserve, null if it is too big or the reserve is
+/// used up.
+unsafe fn reserve_alloc(layout: Layout) -> *mut u8 {
+    let reserve = &mut RESERVE;
+    let start = (reserve.used + layout.align() - 1) & !(layout.align() - 1);
+    if layout.size() > RESERVE_MAX_ALLOC
+        || layout.align() > RESERVE_MAX_ALLOC
+        || start + layout.size() > RESERVE_SIZE
+    {
+        return ptr::null_mut();
+    }
+    reserve.used = start + layout.size();
+    reserve.live += 1;
+    reserve.mem.as_mut_ptr().add(start)
+}
+
+/// Give back an allocation from the reserve.
+///
+/// # Safety
+/// `ptr` must be in the reserve and not freed already.
+pub unsafe fn reserve_free(ptr: *mut u8) {
+    debug_assert!(in_reserve(ptr));
+    RESERVE.live -= 1;
+    if RESERVE.live == 0 {
+        RESERVE.used = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_then_reserve() {
+        static mut CALLS: usize = 0;
+        static mut HANDLER_PTRS: [*mut u8; 2] = [ptr::null_mut(); 2];
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut tries = 0;
+        unsafe {
+            // Without a handler there is no reserve
+            assert!(out_of_memory(layout, ptr::null_mut).is_null());
+
+            // The handler frees enough on the second try
+            fn handler(_: Layout) -> bool {
+                unsafe { CALLS += 1 };
+                true
+            }
+            set_oom_policy(OomPolicy::Retry(handler));
+            let fake = 8 as *mut u8;
+            let ptr = out_of_memory(layout, || {
+                tries += 1;
+                if tries == 2 {
+                    fake
+                } else {
+                    ptr::null_mut()
+                }
+            });
+            assert_eq!((ptr, CALLS), (fake, 2));
+            assert!(out_of_memory(layout, ptr::null_mut).is_null());
+            assert_eq!(CALLS, 2 + RETRIES);
+
+            // What the handler allocates comes from the reserve
+            fn allocating(layout: Layout) -> bool {
+                unsafe {
+                    HANDLER_PTRS = [
+                        out_of_memory(layout, ptr::null_mut),
+                        out_of_memory(Layout::from_size_align(8, 64).unwrap(), ptr::null_mut),
+                    ];
+                    let big = Layout::from_size_align(RESERVE_MAX_ALLOC + 1, 8).unwrap();
+                    assert!(out_of_memory(big, ptr::null_mut).is_null());
+                }
+                false
+            }
+            set_oom_policy(OomPolicy::Retry(allocating));
+            assert!(out_of_memory(layout, ptr::null_mut).is_null());
+            let [a, b] = HANDLER_PTRS;
+            assert!(in_reserve(a) && in_reserve(b));
+            assert_eq!(b as usize % 64, 0);
+
+            // Once everything is freed it starts over
+            reserve_free(a);
+            reserve_free(b);
+            assert!(out_of_memory(layout, ptr::null_mut).is_null());
+            assert_eq!(HANDLER_PTRS[0], a);
+            reserve_free(HANDLER_PTRS[0]);
+            reserve_free(HANDLER_PTRS[1]);
+
+            // A handler that panics doesn't leave the reserve open
+            fn panicking(_: Layout) -> bool {
+                panic!()
+            }
+            set_oom_policy(OomPolicy::Retry(panicking));
+            let panicked = std::panic::catch_unwind(|| out_of_memory(layout, ptr::null_mut));
+            assert!(panicked.is_err());
+            assert!(!IN_HANDLER);
+            set_oom_policy(OomPolicy::Null);
+            assert!(out_of_memory(layout, ptr::null_mut).is_null());
+        }
+        set_oom_policy(OomPolicy::Null);
+    }
+}
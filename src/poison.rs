@@ -0,0 +1,165 @@
+//! Free memory is poisoned ☠️ don't touch it.
+//!
+//! Every `Block` that is freed has its data filled with `POISON_BYTE`. When the
+//! `Block` is handed out again or merged with a neighbor the poison is checked and
+//! if anything wrote to the freed memory we report a use after free.
+//!
+//! Freed blocks are also held in a FIFO quarantine before they can be reused so a
+//! dangling pointer has a much better chance of writing into poisoned memory.
+
+use core::ptr;
+
+use crate::{
+    block::{Block, BlockState},
+    report,
+};
+
+/// Is freed memory poisoned and quarantined.
+pub const ENABLED: bool = cfg!(feature = "poison");
+
+/// The byte freed memory is filled with.
+pub const POISON_BYTE: u8 = 0xDD;
+
+/// The number of freed bytes held in quarantine unless changed with
+/// `set_quarantine_size`.
+pub const DEFAULT_QUARANTINE_SIZE: usize = 256 * 1024;
+
+/// The maximum number of blocks that can be in quarantine at once.
+const QUARANTINE_SLOTS: usize = 1024;
+
+static mut QUARANTINE: Quarantine = Quarantine {
+    blocks: [ptr::null_mut(); QUARANTINE_SLOTS],
+    head: 0,
+    len: 0,
+    bytes: 0,
+    max_bytes: DEFAULT_QUARANTINE_SIZE,
+};
+
+/// Set the number of freed bytes that are held back from reuse.
+///
+/// A size of zero turns the quarantine off, freed memory is still poisoned.
+pub fn set_quarantine_size(bytes: usize) {
+    unsafe { QUARANTINE.max_bytes = bytes };
+}
+
+/// Fill `len` bytes starting at `ptr` with poison.
+///
+/// # Safety
+/// `ptr` must be valid for `len` bytes.
+pub unsafe fn fill(ptr: *mut u8, len: usize) {
+    ptr::write_bytes(ptr, POISON_BYTE, len);
+}
+
+/// Poison the data of `blk`.
+///
+/// # Safety
+/// `blk` must be a valid `Block`.
+pub unsafe fn poison(blk: *mut Block) {
+    fill(blk.add(1).cast(), (*blk).size);
+}
+
+/// Check that the data of `blk` is still poisoned.
+///
+/// On failure the offset of the first byte that was written to is returned.
+///
+/// # Safety
+/// `blk` must be a valid `Block`.
+pub unsafe fn check(blk: *const Block) -> Result<(), usize> {
+    let data = blk.add(1).cast::<u8>();
+    for off in 0..(*blk).size {
+        if *data.add(off) != POISON_BYTE {
+            return Err(off);
+        }
+    }
+    Ok(())
+}
+
+/// Check the poison of `blk` and abort if it has been written to.
+///
+/// `op` is the name of what was about to reuse `blk`.
+///
+/// # Safety
+/// `blk` must be a valid free or quarantined `Block`.
+pub unsafe fn verify(blk: *const Block, op: &str) {
+    if !ENABLED {
+        return;
+    }
+    if let Err(off) = check(blk) {
+        let data = blk.add(1).cast::<u8>();
+        report::abort(format_args!(
+            "use after free detected in {}: freed {:?} of size {} was written to \
+             at offset {} (found {:#04x})",
+            op,
+            data,
+            (*blk).size,
+            off,
+            *data.add(off)
+        ))
+    }
+}
+
+/// Put `blk` into quarantine.
+///
+/// Afterwards call `evict` until it returns null, each block it returns has left
+/// quarantine and must actually be freed.
+///
+/// # Safety
+/// `blk` must be a valid `Block` that has already been poisoned.
+pub unsafe fn quarantine(blk: *mut Block) {
+    let q = &mut QUARANTINE;
+    debug_assert!(q.len < QUARANTINE_SLOTS, "quarantine was not drained");
+    (*blk).free = BlockState::Quarantined;
+    q.blocks[(q.head + q.len) % QUARANTINE_SLOTS] = blk;
+    q.len += 1;
+    q.bytes += (*blk).size;
+}
+
+/// Returns the oldest quarantined block if the quarantine is over budget, null
+/// otherwise.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn evict() -> *mut Block {
+    let q = &mut QUARANTINE;
+    if q.len == 0 || (q.bytes <= q.max_bytes && q.len < QUARANTINE_SLOTS) {
+        return ptr::null_mut();
+    }
+    let blk = q.blocks[q.head];
+    q.head = (q.head + 1) % QUARANTINE_SLOTS;
+    q.len -= 1;
+    q.bytes -= (*blk).size;
+    verify(blk, "quarantine");
+    blk
+}
+
+struct Quarantine {
+    /// Ring buffer of quarantined blocks, the oldest is at `head`.
+    blocks: [*mut Block; QUARANTINE_SLOTS],
+    head: usize,
+    len: usize,
+    /// The sum of `Block.size` of every quarantined block.
+    bytes: usize,
+    max_bytes: usize,
+}
+
+unsafe impl Send for Quarantine {}
+unsafe impl Sync for Quarantine {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn poison_catches_write() {
+        let mut buf = [0_usize; 16];
+        let blk = buf.as_mut_ptr().cast::<Block>();
+        unsafe {
+            Block::from_raw(blk.cast(), 32, ptr::null_mut());
+            poison(blk);
+            assert_eq!(check(blk), Ok(()));
+
+            *blk.add(1).cast::<u8>().add(5) = 0;
+            assert_eq!(check(blk), Err(5));
+        }
+    }
+}
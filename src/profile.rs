@@ -0,0 +1,219 @@
+//! Where is all the memory going 🔥
+//!
+//! With the `profile` feature allocations are sampled on average once every
+//! `set_profile_sample_interval` bytes. The gaps between samples are drawn from
+//! an exponential distribution so every byte has the same chance of being
+//! picked, however it was allocated. A sampled block remembers the stack it was
+//! allocated from and leaves the live profile when it is freed.
+//!
+//! `dump_heap_profile` writes the live and cumulative samples in the gperftools
+//! heap profile text format, `pprof` reads it directly and scales the samples
+//! back up to real sizes.
+//!
+//! ```notrust
+//! pprof --svg ./service heap.prof > heap.svg
+//! ```
+//!
+//! Stacks come from walking frame pointers, build with
+//! `RUSTFLAGS="-C force-frame-pointers=yes"`.
+
+use core::{cmp, fmt::Write};
+
+use crate::{
+    backtrace::{self, SiteTable, Stack, STACKS},
+    block::Block,
+    random::{self, Rng},
+    report::RawFd,
+};
+
+/// Is the heap profiler sampling allocations.
+pub const ENABLED: bool = cfg!(feature = "profile");
+
+/// The mean number of bytes allocated between samples.
+pub const DEFAULT_SAMPLE_INTERVAL: usize = 512 * 1024;
+
+static mut PROFILE: Profile = Profile {
+    interval: DEFAULT_SAMPLE_INTERVAL,
+    until_sample: 0,
+    started: false,
+    samples: SiteTable::EMPTY,
+    counts: [Counts::EMPTY; STACKS],
+};
+
+/// The samples taken from one stack.
+#[derive(Clone, Copy, Debug)]
+struct Counts {
+    live_count: usize,
+    live_bytes: usize,
+    alloc_count: usize,
+    alloc_bytes: usize,
+}
+
+impl Counts {
+    const EMPTY: Counts = Counts {
+        live_count: 0,
+        live_bytes: 0,
+        alloc_count: 0,
+        alloc_bytes: 0,
+    };
+}
+
+struct Profile {
+    interval: usize,
+    /// Bytes left to allocate before the next sample.
+    until_sample: usize,
+    /// Has the first interval been drawn.
+    started: bool,
+    /// The stacks of the sampled blocks that are still live.
+    samples: SiteTable,
+    /// Indexed like the stacks in `samples`.
+    counts: [Counts; STACKS],
+}
+
+unsafe impl Send for Profile {}
+unsafe impl Sync for Profile {}
+
+impl Profile {
+    /// Add the sampled `block` of `size` bytes allocated from `stack`.
+    fn record(&mut self, block: usize, size: usize, stack: &Stack) {
+        if let Some(idx) = self.samples.intern(stack) {
+            if self.samples.insert(block, idx).is_ok() {
+                let c = &mut self.counts[idx as usize];
+                c.live_count += 1;
+                c.live_bytes += size;
+                c.alloc_count += 1;
+                c.alloc_bytes += size;
+            }
+        }
+    }
+
+    /// Drop `block` of `size` bytes from the live samples if it is one.
+    fn forget(&mut self, block: usize, size: usize) {
+        if let Some(idx) = self.samples.remove(block) {
+            let c = &mut self.counts[idx as usize];
+            c.live_count -= 1;
+            c.live_bytes -= size;
+        }
+    }
+}
+
+/// Sample on average once every `bytes` allocated, the default is 512 KiB.
+///
+/// Samples already taken are kept, changing the interval part way through makes
+/// the profile harder to scale back up.
+pub fn set_profile_sample_interval(bytes: usize) {
+    unsafe {
+        PROFILE.interval = cmp::max(bytes, 1);
+        PROFILE.started = false;
+    }
+}
+
+/// The number of bytes until the next sample, exponentially distributed with
+/// a mean of `mean`.
+fn next_interval(rng: &mut Rng, mean: usize) -> usize {
+    // Uniform in (0, 1], never zero so the log is finite
+    let u = ((rng.next_u64() >> 11) + 1) as f64 / (1_u64 << 53) as f64;
+    (-u.ln() * mean as f64) as usize
+}
+
+/// Count `blk` towards the next sample and record its stack if it is sampled.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn on_alloc(blk: *mut Block) {
+    let p = &mut PROFILE;
+    let size = (*blk).size;
+    if !p.started {
+        p.started = true;
+        p.until_sample = next_interval(random::global(), p.interval);
+    }
+    if size < p.until_sample {
+        p.until_sample -= size;
+        return;
+    }
+    p.until_sample = next_interval(random::global(), p.interval);
+
+    p.record(blk as usize, size, &Stack::capture());
+}
+
+/// Drop `blk` from the live profile if it was sampled.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn on_free(blk: *mut Block) {
+    PROFILE.forget(blk as usize, (*blk).size);
+}
+
+/// Write the sampled heap to `fd` in the gperftools heap profile format.
+///
+/// Each stack gets the live objects and bytes followed by everything ever
+/// sampled from it, then come the mappings of the process so `pprof` can find
+/// the symbols. `fd` is a raw file descriptor, from `File::as_raw_fd` for
+/// example, nothing is allocated while writing.
+pub fn dump_heap_profile(fd: i32) {
+    let mut out = RawFd(fd as usize);
+    let p = unsafe { &PROFILE };
+    let counts = &p.counts[..p.samples.len()];
+
+    let mut total = Counts::EMPTY;
+    for c in counts {
+        total.live_count += c.live_count;
+        total.live_bytes += c.live_bytes;
+        total.alloc_count += c.alloc_count;
+        total.alloc_bytes += c.alloc_bytes;
+    }
+    let _ = writeln!(
+        out,
+        "heap profile: {:>6}: {:>8} [{:>6}: {:>8}] @ heap_v2/{}",
+        total.live_count, total.live_bytes, total.alloc_count, total.alloc_bytes, p.interval
+    );
+    for (idx, c) in counts.iter().enumerate() {
+        let _ = write!(
+            out,
+            "{:>6}: {:>8} [{:>6}: {:>8}] @",
+            c.live_count, c.live_bytes, c.alloc_count, c.alloc_bytes
+        );
+        for frame in p.samples.stack(idx as u32).frames() {
+            let _ = write!(out, " {:#x}", frame);
+        }
+        let _ = writeln!(out);
+    }
+    let _ = write!(out, "\nMAPPED_LIBRARIES:\n");
+    backtrace::write_maps(fd as usize);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn geometric_interval() {
+        let mut rng = Rng::new(1);
+        let n = 10_000;
+        let mean = (0..n).map(|_| next_interval(&mut rng, 4096)).sum::<usize>() / n;
+        assert!(mean > 3800 && mean < 4400, "mean interval {}", mean);
+    }
+
+    #[test]
+    fn full_profile() {
+        // Far too big for the stack, all zeros is an empty profile
+        let mut p = unsafe {
+            let layout = std::alloc::Layout::new::<Profile>();
+            Box::from_raw(std::alloc::alloc_zeroed(layout) as *mut Profile)
+        };
+        let stack = Stack::capture();
+        let blocks = (1..=20_000).map(|i| i * 16);
+        for b in blocks.clone() {
+            p.record(b, 16, &stack);
+        }
+        let live = p.counts[0].live_count;
+        assert!(live > 0 && live < 20_000);
+
+        // Freeing a block that was never sampled must not hang on a full table
+        for b in blocks {
+            p.forget(b, 16);
+        }
+        assert_eq!((p.counts[0].live_count, p.counts[0].live_bytes), (0, 0));
+        assert_eq!(p.counts[0].alloc_count, live);
+    }
+}
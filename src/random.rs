@@ -0,0 +1,92 @@
+//! Random numbers 🎲 seeded by the kernel.
+
+use crate::{report, syscall};
+
+/// Is the heap's base address and the placement of allocations randomized.
+pub const RANDOMIZE: bool = cfg!(feature = "randomize");
+
+/// Used for a seed of zero, the state of an `Rng` must never be zero.
+const FALLBACK_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
+static mut RNG: Rng = Rng { state: 0 };
+
+/// Fill `buf` with random bytes from the kernel using `GETRANDOM`.
+pub fn fill(buf: &mut [u8]) -> Result<(), ()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let n = unsafe { syscall!(GETRANDOM, rest.as_mut_ptr(), rest.len(), 0) };
+        if syscall::is_err(n) {
+            return Err(());
+        }
+        filled += n;
+    }
+    Ok(())
+}
+
+/// A random `u64` straight from the kernel, aborts if the kernel won't give
+/// us one. Anything we could make up ourselves is too easy to guess to be a key.
+pub fn secret() -> u64 {
+    let mut buf = [0_u8; 8];
+    if fill(&mut buf).is_err() {
+        report::abort(format_args!("GETRANDOM failed, there is no secret to use"));
+    }
+    u64::from_ne_bytes(buf)
+}
+
+/// The allocators global `Rng`, it is seeded the first time it is used.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+pub unsafe fn global() -> &'static mut Rng {
+    if RNG.state == 0 {
+        RNG = Rng::new(secret());
+    }
+    &mut RNG
+}
+
+/// A xorshift64* pseudo random number generator.
+///
+/// This is fast and small, it is __not__ cryptographically secure.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub const fn new(seed: u64) -> Self {
+        Self {
+            state: if seed == 0 { FALLBACK_SEED } else { seed },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A random number in `0..n`, `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rng_below() {
+        let mut rng = Rng::new(0);
+        for n in 1..100 {
+            assert!(rng.below(n) < n);
+        }
+
+        // Same seed, same numbers
+        let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+        assert!((0..100).all(|_| a.next_u64() == b.next_u64()));
+    }
+}
@@ -0,0 +1,95 @@
+//! A little no man's land after every allocation 🚧
+//!
+//! ```notrust
+//! |---------|___________________________|+++++++++|
+//!   metadata     layout.size() bytes       redzone
+//! ```
+
+use core::{alloc::Layout, ptr};
+
+use crate::report;
+
+/// Are redzones added to allocations.
+pub const ENABLED: bool = cfg!(feature = "redzone");
+
+/// The number of bytes of padding that follow every allocation.
+pub const REDZONE_SIZE: usize = 16;
+
+/// The byte every redzone is painted with.
+pub const REDZONE_BYTE: u8 = 0xFA;
+
+/// The extra bytes that need to be requested for an allocation.
+#[inline]
+pub const fn padding() -> usize {
+    if ENABLED {
+        REDZONE_SIZE
+    } else {
+        0
+    }
+}
+
+/// Fill the redzone that starts `size` bytes after `ptr`.
+///
+/// # Safety
+/// `ptr` must be valid for `size + REDZONE_SIZE` bytes.
+pub unsafe fn paint(ptr: *mut u8, size: usize) {
+    ptr::write_bytes(ptr.add(size), REDZONE_BYTE, REDZONE_SIZE);
+}
+
+/// Check the redzone that starts `size` bytes after `ptr`.
+///
+/// On failure the offset from `ptr` of the first corrupted byte is returned.
+///
+/// # Safety
+/// `ptr` must be valid for `size + REDZONE_SIZE` bytes.
+pub unsafe fn check(ptr: *const u8, size: usize) -> Result<(), usize> {
+    for off in size..size + REDZONE_SIZE {
+        if *ptr.add(off) != REDZONE_BYTE {
+            return Err(off);
+        }
+    }
+    Ok(())
+}
+
+/// Check the redzone of the allocation at `ptr` and abort if it has been written to.
+///
+/// `op` is the name of the allocator function we were called from.
+///
+/// # Safety
+/// `ptr` must have been returned by the allocator with `layout`.
+pub unsafe fn verify(ptr: *const u8, layout: Layout, op: &str) {
+    if !ENABLED {
+        return;
+    }
+    if let Err(off) = check(ptr, layout.size()) {
+        report::abort(format_args!(
+            "heap buffer overflow detected in {}: {:?} of size {} has its redzone \
+             corrupted at offset {} (found {:#04x})",
+            op,
+            ptr,
+            layout.size(),
+            off,
+            *ptr.add(off)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redzone_first_corrupt_byte() {
+        let mut buf = [0_u8; 8 + REDZONE_SIZE];
+        unsafe {
+            paint(buf.as_mut_ptr(), 8);
+            // Writing to the data is fine
+            buf[7] = 1;
+            assert_eq!(check(buf.as_ptr(), 8), Ok(()));
+
+            buf[8 + 3] = 0;
+            buf[8 + 9] = 0;
+            assert_eq!(check(buf.as_ptr(), 8), Err(11));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Tell someone something went wrong without asking the heap for help 📣
+
+use core::fmt::{self, Write};
+
+use crate::syscall;
+
+/// The file descriptor for standard error.
+pub const STDERR: usize = 2;
+/// `EINTR` the syscall was interrupted and should be retried.
+const EINTR: isize = -4;
+
+/// A `fmt::Write`r that goes straight to the `WRITE` syscall.
+///
+/// Nothing is buffered and nothing is allocated so this is safe to use from
+/// inside the allocator (or a signal handler).
+pub struct RawFd(pub usize);
+
+impl RawFd {
+    /// Write all of `bytes`.
+    pub fn write_bytes(&mut self, mut bytes: &[u8]) -> fmt::Result {
+        while !bytes.is_empty() {
+            let n = unsafe { syscall!(WRITE, self.0, bytes.as_ptr(), bytes.len()) } as isize;
+            if n == EINTR {
+                continue;
+            }
+            if n <= 0 {
+                return Err(fmt::Error);
+            }
+            bytes = &bytes[n as usize..];
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Write for RawFd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes())
+    }
+}
+
+/// Write a line prefixed with `ralloc: ` to stderr.
+pub fn write(args: fmt::Arguments<'_>) {
+    let mut out = RawFd(STDERR);
+    let _ = out.write_str("ralloc: ");
+    let _ = out.write_fmt(args);
+    let _ = out.write_str("\n");
+}
+
+/// Write a line to stderr then abort the process.
+pub fn abort(args: fmt::Arguments<'_>) -> ! {
+    write(args);
+    std::process::abort()
+}
@@ -1,5 +1,14 @@
 pub mod sys_num;
 
+/// The kernel returns `-errno` on failure, any value in this range is an error.
+const MAX_ERRNO: usize = 4095;
+
+/// Did the syscall that returned `ret` fail.
+#[inline(always)]
+pub fn is_err(ret: usize) -> bool {
+    ret >= (-(MAX_ERRNO as isize)) as usize
+}
+
 #[inline(always)]
 pub unsafe fn syscall0(n: usize) -> usize {
     let ret: usize;
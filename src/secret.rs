@@ -0,0 +1,145 @@
+//! A heap for keys and passwords 🔐
+//!
+//! ```notrust
+//! |guard|___data pages___|guard|
+//! ```
+//!
+//! Every allocation gets its own pages surrounded by `PROT_NONE` guard pages.
+//! The data is locked in RAM so it never hits swap, left out of core dumps and
+//! wiped before it is unmapped. This is slow and wasteful on purpose, only put
+//! secrets here.
+
+use core::{
+    alloc::{AllocError, AllocRef, Layout},
+    cmp,
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    mmap::{self, MADV_DONTDUMP, PROT_NONE, PROT_READ, PROT_WRITE},
+    util::{page_align, PAGE_SIZE},
+    wipe,
+};
+
+/// An allocator for secrets, separate from the general heap.
+///
+/// ```ignore
+/// let mut key = Vec::with_capacity_in(32, &SecretHeap);
+/// key.extend_from_slice(b"hunter2");
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SecretHeap;
+
+/// The bytes of data pages needed for `layout`.
+fn data_len(layout: Layout) -> usize {
+    page_align(cmp::max(layout.size(), 1))
+}
+
+unsafe impl AllocRef for &SecretHeap {
+    fn alloc(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.align() > PAGE_SIZE {
+            return Err(AllocError);
+        }
+        let len = data_len(layout);
+        unsafe {
+            let start = mmap::map(ptr::null(), len + 2 * PAGE_SIZE, PROT_NONE)
+                .map_err(|()| AllocError)?;
+            let data = start.add(PAGE_SIZE);
+            // If memory can't be locked we would rather fail than have it swapped
+            let res = mmap::protect(data, len, PROT_READ | PROT_WRITE)
+                .and_then(|()| mmap::lock(data, len))
+                .and_then(|()| mmap::advise(data, len, MADV_DONTDUMP));
+            if res.is_err() {
+                let _ = mmap::unmap(start, len + 2 * PAGE_SIZE);
+                return Err(AllocError);
+            }
+            Ok(NonNull::slice_from_raw_parts(
+                NonNull::new_unchecked(data),
+                len,
+            ))
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        let len = data_len(layout);
+        let data = ptr.as_ptr();
+        scrub(data, len);
+        let _ = mmap::unmap(data.sub(PAGE_SIZE), len + 2 * PAGE_SIZE);
+    }
+}
+
+/// Wipe and unlock the data pages before they are unmapped.
+unsafe fn scrub(data: *mut u8, len: usize) {
+    wipe::wipe(data, len);
+    let _ = mmap::unlock(data, len);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Does writing to `addr` kill a forked child with `SIGSEGV`.
+    fn faults(addr: *mut u8) -> bool {
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                addr.write_volatile(1);
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+        }
+    }
+
+    /// The `VmFlags` of the mapping starting at `addr` in `/proc/self/smaps`.
+    fn vm_flags(addr: *mut u8) -> String {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let start = format!("{:x}-", addr as usize);
+        smaps
+            .lines()
+            .skip_while(|l| !l.starts_with(&start))
+            .find(|l| l.starts_with("VmFlags:"))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn secret_alloc() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let heap = &SecretHeap;
+        let mem = heap.alloc(layout).unwrap();
+        assert_eq!(mem.len(), PAGE_SIZE);
+        unsafe {
+            let data = mem.as_ptr() as *mut u8;
+            assert_eq!(data as usize % PAGE_SIZE, 0);
+            ptr::write_bytes(data, 0x42, 100);
+
+            // Locked, left out of core dumps and fenced on both sides
+            let flags = vm_flags(data);
+            assert!(flags.contains(" lo") && flags.contains(" dd"), "{}", flags);
+            assert!(!faults(data.add(PAGE_SIZE - 1)));
+            assert!(faults(data.add(PAGE_SIZE)));
+            assert!(faults(data.sub(1)));
+
+            heap.dealloc(NonNull::new_unchecked(data), layout);
+        }
+    }
+
+    #[test]
+    fn wiped_on_dealloc() {
+        let layout = Layout::from_size_align(5000, 8).unwrap();
+        let heap = &SecretHeap;
+        let mem = heap.alloc(layout).unwrap();
+        unsafe {
+            let data = mem.as_ptr() as *mut u8;
+            ptr::write_bytes(data, 0x42, mem.len());
+            // What dealloc does before the pages are gone
+            scrub(data, mem.len());
+            let bytes = core::slice::from_raw_parts(data, mem.len());
+            assert!(bytes.iter().all(|b| *b == 0));
+            heap.dealloc(NonNull::new_unchecked(data), layout);
+        }
+    }
+}
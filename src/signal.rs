@@ -0,0 +1,86 @@
+//! Catching signals with `RT_SIGACTION` 🚦
+//!
+//! These are the kernel's structures not libc's, they differ.
+
+use crate::syscall;
+
+/// Invalid memory reference.
+pub const SIGSEGV: usize = 11;
+/// The handler wants the `SigInfo` and context arguments.
+const SA_SIGINFO: u64 = 0x0000_0004;
+/// Run the handler on the alternate signal stack if one was set up.
+const SA_ONSTACK: u64 = 0x0800_0000;
+/// We provide the trampoline that calls `RT_SIGRETURN`, x86_64 requires this.
+const SA_RESTORER: u64 = 0x0400_0000;
+/// The size of the kernel's signal set.
+const SIGSET_SIZE: usize = 8;
+
+/// A signal handler that takes the extra `SigInfo` argument.
+pub type Handler = extern "C" fn(sig: i32, info: *mut SigInfo, ctx: *mut u8);
+
+/// The kernel's `struct sigaction`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SigAction {
+    handler: usize,
+    flags: u64,
+    restorer: usize,
+    mask: u64,
+}
+
+impl SigAction {
+    /// The default action for a signal.
+    pub const fn empty() -> Self {
+        Self {
+            handler: 0,
+            flags: 0,
+            restorer: 0,
+            mask: 0,
+        }
+    }
+
+    pub fn new(handler: Handler) -> Self {
+        Self {
+            handler: handler as usize,
+            flags: SA_SIGINFO | SA_ONSTACK | SA_RESTORER,
+            restorer: restore_rt as usize,
+            mask: 0,
+        }
+    }
+}
+
+/// The start of the kernel's `siginfo_t`, we only ever read it.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SigInfo {
+    pub signo: i32,
+    pub errno: i32,
+    pub code: i32,
+    _pad: i32,
+    /// For `SIGSEGV` and `SIGBUS` the address that faulted.
+    pub addr: usize,
+}
+
+/// Install `new` as the action for `sig`, the previous action is returned.
+pub unsafe fn action(sig: usize, new: &SigAction) -> Result<SigAction, ()> {
+    let mut old = SigAction::empty();
+    let ret = syscall!(
+        RT_SIGACTION,
+        sig,
+        new as *const SigAction,
+        &mut old as *mut SigAction,
+        SIGSET_SIZE
+    );
+    if syscall::is_err(ret) {
+        Err(())
+    } else {
+        Ok(old)
+    }
+}
+
+/// When a signal handler returns it returns here, the kernel has left the saved
+/// context on the stack for `RT_SIGRETURN` to restore.
+#[naked]
+unsafe extern "C" fn restore_rt() {
+    asm!("mov rax, 15", "syscall", options(noreturn));
+}
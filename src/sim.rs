@@ -0,0 +1,103 @@
+//! A pretend program break 🧪 inside a buffer we own.
+//!
+//! `SimBreak` hands out memory from the front of a fixed buffer so a `Heap` can
+//! run without touching the real program break. Every pointer it gives out is
+//! derived from the buffer's own pointer, no pointer is made from an integer
+//! and there is no inline assembly, so the `Block` logic can run under Miri.
+//! The run has to be built without `safe-linking` and `randomize`, they mix
+//! random numbers from the kernel into the heap.
+//!
+//! Only built for tests and with the `sim` feature.
+
+use core::{mem, ptr};
+
+use crate::breaks::Break;
+
+/// A fake program break at the start of a zeroed buffer.
+pub struct SimBreak {
+    /// The start of the buffer, word aligned.
+    mem: *mut u8,
+    /// The size of the buffer in bytes.
+    len: usize,
+    /// How far into the buffer the break is.
+    brk: usize,
+}
+
+unsafe impl Send for SimBreak {}
+
+impl SimBreak {
+    /// A break that can grow to at most `len` bytes.
+    pub fn new(len: usize) -> Self {
+        let words = vec![0_usize; len / mem::size_of::<usize>()].into_boxed_slice();
+        let len = words.len() * mem::size_of::<usize>();
+        Self {
+            mem: Box::into_raw(words).cast(),
+            len,
+            brk: 0,
+        }
+    }
+
+    /// The number of bytes below the break.
+    pub fn used(&self) -> usize {
+        self.brk
+    }
+}
+
+impl Break for SimBreak {
+    unsafe fn sbrk(&mut self, size: isize) -> Result<*const u8, ()> {
+        let new = self.brk as isize + size;
+        if new < 0 || new as usize > self.len {
+            return Err(());
+        }
+        let old = self.mem.add(self.brk);
+        self.brk = new as usize;
+        Ok(old)
+    }
+}
+
+impl Drop for SimBreak {
+    fn drop(&mut self) {
+        let words = self.len / mem::size_of::<usize>();
+        unsafe {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                self.mem.cast::<usize>(),
+                words,
+            )))
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::heap::Heap;
+
+    #[test]
+    fn sim_heap() {
+        let mut heap = Heap::new(SimBreak::new(4096));
+        unsafe {
+            let a = heap.malloc_block(24);
+            let b = heap.malloc_block(100);
+            let c = heap.malloc_block(8);
+            assert_eq!(heap.blocks().count(), 3);
+            assert_eq!(a as usize % mem::align_of::<usize>(), 0);
+
+            // A freed block in the middle is reused
+            heap.free(b);
+            assert_eq!(heap.malloc_block(100), b);
+            heap.free(b);
+
+            // Freeing the end gives everything back
+            heap.free(c);
+            heap.free(a);
+            assert!(heap.base.is_null());
+            assert_eq!(heap.brk().used(), 0);
+
+            // Running out of buffer is an error, not a write past the end
+            let mut brk = SimBreak::new(64);
+            assert!(brk.sbrk(65).is_err());
+            assert!(brk.sbrk(64).is_ok());
+            assert_eq!(brk.used(), 64);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Keeping count 🧮 of how big the heap is and how often we ask the kernel for help.
+
+use crate::block::BlockState;
+
+static mut STATS: Stats = Stats {
+    heap_size: 0,
+    peak_heap_size: 0,
+    free_bytes: 0,
+    brk: 0,
+    mmap: 0,
+    munmap: 0,
+    mprotect: 0,
+    madvise: 0,
+};
+
+/// A snapshot of the allocator's counters, see `stats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Bytes between the start and the end of the heap.
+    pub heap_size: usize,
+    /// The largest `heap_size` has been.
+    pub peak_heap_size: usize,
+    /// Bytes in free blocks that are waiting to be reused.
+    pub free_bytes: usize,
+    /// The number of each syscall made.
+    pub brk: usize,
+    pub mmap: usize,
+    pub munmap: usize,
+    pub mprotect: usize,
+    pub madvise: usize,
+}
+
+/// The syscalls that are counted.
+#[derive(Clone, Copy, Debug)]
+pub enum Syscall {
+    Brk,
+    Mmap,
+    Munmap,
+    Mprotect,
+    Madvise,
+}
+
+/// Count a call to `call`.
+pub fn count(call: Syscall) {
+    unsafe {
+        let n = match call {
+            Syscall::Brk => &mut STATS.brk,
+            Syscall::Mmap => &mut STATS.mmap,
+            Syscall::Munmap => &mut STATS.munmap,
+            Syscall::Mprotect => &mut STATS.mprotect,
+            Syscall::Madvise => &mut STATS.madvise,
+        };
+        *n += 1;
+    }
+}
+
+/// The end of the heap moved by `delta` bytes.
+pub fn heap_moved(delta: isize) {
+    unsafe {
+        STATS.heap_size = (STATS.heap_size as isize + delta) as usize;
+        if STATS.heap_size > STATS.peak_heap_size {
+            STATS.peak_heap_size = STATS.heap_size;
+        }
+    }
+}
+
+/// The bytes between the start and the end of the heap right now.
+#[inline]
+pub fn heap_size() -> usize {
+    unsafe { STATS.heap_size }
+}
+
+/// The allocator's counters right now.
+///
+/// `free_bytes` is found by walking the heap so this is not free.
+pub fn stats() -> Stats {
+    unsafe {
+        let mut stats = STATS;
+        for b in crate::HEAP.blocks() {
+            if (*b).free == BlockState::Free {
+                stats.free_bytes += (*b).size;
+            }
+        }
+        stats
+    }
+}
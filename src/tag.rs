@@ -0,0 +1,119 @@
+//! Who is holding all this memory 🏷️
+//!
+//! Every block remembers the tag of the thread that allocated it, `with_tag`
+//! sets the tag for a closure and `tag_stats` says how much each tag has live.
+//! Give each subsystem its own tag and you can see which one is growing.
+//!
+//! ```ignore
+//! const PARSER: u8 = 1;
+//! let ast = ralloc::with_tag(PARSER, || parse(&src));
+//! println!("{:?}", ralloc::tag_stats()[PARSER as usize]);
+//! ```
+//!
+//! A `realloc` keeps the tag the block already had, whatever tag the thread has
+//! now. Tag zero is everything allocated outside of `with_tag`.
+
+use crate::util::Restore;
+
+/// The number of different tags.
+pub const TAGS: usize = 256;
+
+static mut STATS: [TagStats; TAGS] = [TagStats::EMPTY; TAGS];
+/// The `live_bytes` of every tag added up.
+static mut LIVE_BYTES: usize = 0;
+
+/// The tag of allocations made by this thread right now.
+#[thread_local]
+static mut TAG: u8 = 0;
+
+/// What one tag holds, see `tag_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TagStats {
+    /// The sizes asked for of the blocks still in use.
+    pub live_bytes: usize,
+    pub live_count: usize,
+    /// Every allocation ever made with this tag.
+    pub total_count: usize,
+}
+
+impl TagStats {
+    const EMPTY: TagStats = TagStats {
+        live_bytes: 0,
+        live_count: 0,
+        total_count: 0,
+    };
+}
+
+/// Run `f` with every allocation it makes on this thread tagged `tag`.
+pub fn with_tag<R>(tag: u8, f: impl FnOnce() -> R) -> R {
+    let _restore = unsafe { Restore::set(&mut TAG, tag) };
+    f()
+}
+
+/// The tag new allocations on this thread get.
+#[inline]
+pub fn current() -> u8 {
+    unsafe { TAG }
+}
+
+/// The live and total counts of every tag, indexed by tag.
+pub fn tag_stats() -> [TagStats; TAGS] {
+    unsafe { STATS }
+}
+
+/// The bytes `tag` has live.
+#[inline]
+pub fn live_bytes(tag: u8) -> usize {
+    unsafe { STATS[tag as usize].live_bytes }
+}
+
+/// The bytes live under all tags together.
+#[inline]
+pub fn total_live_bytes() -> usize {
+    unsafe { LIVE_BYTES }
+}
+
+/// Count a new allocation of `size` bytes under `tag`.
+pub fn on_alloc(tag: u8, size: usize) {
+    let stats = unsafe { &mut STATS[tag as usize] };
+    stats.live_bytes += size;
+    stats.live_count += 1;
+    stats.total_count += 1;
+    unsafe { LIVE_BYTES += size };
+}
+
+/// Take a freed allocation of `size` bytes away from `tag`.
+pub fn on_free(tag: u8, size: usize) {
+    let stats = unsafe { &mut STATS[tag as usize] };
+    stats.live_bytes -= size;
+    stats.live_count -= 1;
+    unsafe { LIVE_BYTES -= size };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nested_tags() {
+        assert_eq!(current(), 0);
+        let inner = with_tag(200, || {
+            let inner = with_tag(201, current);
+            assert_eq!(current(), 200);
+            inner
+        });
+        assert_eq!((inner, current()), (201, 0));
+
+        on_alloc(202, 100);
+        on_alloc(202, 20);
+        on_free(202, 100);
+        assert_eq!(
+            tag_stats()[202],
+            TagStats {
+                live_bytes: 20,
+                live_count: 1,
+                total_count: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! A recording of everything the allocator was asked to do 📼
+//!
+//! With the `trace` feature and `RALLOC_TRACE=/path/to/trace` set, every call to
+//! `alloc`, `alloc_zeroed`, `realloc` and `dealloc` is written to that file.
+//! Events are collected in a fixed ring per thread and the whole ring is
+//! appended with one raw `WRITE` when it fills up, so tracing never allocates.
+//! A thread's ring is flushed when the thread finishes and the main thread's at
+//! exit. Threads still running when the process exits lose their last events,
+//! call `flush_trace` from them first if you need those.
+//!
+//! The trace file is opened and the hooks are set up by a constructor before
+//! `main`, not from inside the allocator.
+//!
+//! The file starts with a 16 byte header, the magic `RALLOCTR` then the format
+//! version and the size of an event as `u32`s. The events follow, every field is
+//! in native byte order.
+//!
+//! ```notrust
+//! offset  size  field
+//!      0     8  time    nanoseconds of CLOCK_MONOTONIC
+//!      8     4  tid     thread id from GETTID
+//!     12     4  op      1 alloc, 2 alloc_zeroed, 3 realloc, 4 dealloc
+//!     16     8  ptr     the pointer returned, or freed by dealloc
+//!     24     8  old     the pointer passed to realloc, otherwise 0
+//!     32     8  size    the size asked for, the new size for realloc
+//!     40     8  align   the alignment asked for
+//! ```
+//!
+//! Each thread's events are in order but chunks from different threads are
+//! interleaved, sort by `time` to get one timeline.
+
+use core::{mem, slice};
+
+use crate::{report::RawFd, syscall, util};
+
+/// Can allocations be traced.
+pub const ENABLED: bool = cfg!(feature = "trace");
+
+/// Marks the start of a trace file.
+pub const MAGIC: [u8; 8] = *b"RALLOCTR";
+/// The version of the trace format.
+pub const VERSION: u32 = 1;
+
+/// Events buffered per thread before they are written out.
+const RING_LEN: usize = 128;
+const CLOCK_MONOTONIC: usize = 1;
+const O_WRONLY: usize = 0o1;
+const O_CREAT: usize = 0o100;
+const O_TRUNC: usize = 0o1000;
+const O_APPEND: usize = 0o2000;
+const O_CLOEXEC: usize = 0o2000000;
+
+static mut FILE: File = File::Unknown;
+
+/// The pthread key whose destructor flushes a thread's ring as it finishes.
+static mut THREAD_EXIT: Option<libc::pthread_key_t> = None;
+
+/// Runs `init` before `main` like a C constructor.
+#[cfg(feature = "trace")]
+#[used]
+#[link_section = ".init_array"]
+static INIT: extern "C" fn() = init;
+
+/// Only threads of programs built with `trace` pay for a ring.
+#[cfg(feature = "trace")]
+#[thread_local]
+static mut RING: Ring = Ring {
+    len: 0,
+    tid: 0,
+    events: [Event::EMPTY; RING_LEN],
+};
+
+/// What the allocator was asked to do.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Op {
+    Alloc = 1,
+    AllocZeroed = 2,
+    Realloc = 3,
+    Dealloc = 4,
+}
+
+/// One call into the allocator as it is laid out in the trace file.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Event {
+    pub time: u64,
+    pub tid: u32,
+    pub op: u32,
+    pub ptr: u64,
+    pub old: u64,
+    pub size: u64,
+    pub align: u64,
+}
+
+impl Event {
+    const EMPTY: Event = Event {
+        time: 0,
+        tid: 0,
+        op: 0,
+        ptr: 0,
+        old: 0,
+        size: 0,
+        align: 0,
+    };
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum File {
+    /// We have not looked at `RALLOC_TRACE` yet.
+    Unknown,
+    Off,
+    Open(usize),
+}
+
+struct Ring {
+    len: usize,
+    /// Zero until the thread's first event.
+    tid: u32,
+    events: [Event; RING_LEN],
+}
+
+#[repr(C)]
+struct Timespec {
+    sec: i64,
+    nsec: i64,
+}
+
+/// The trace file, opened and given its header the first time we look.
+fn file() -> File {
+    unsafe {
+        if FILE == File::Unknown {
+            FILE = match util::env(b"RALLOC_TRACE\0") {
+                Some(path) if ENABLED => open(path),
+                _ => File::Off,
+            };
+        }
+        FILE
+    }
+}
+
+unsafe fn open(path: &[u8]) -> File {
+    // `env` hands out the value without the nul but it is still there after it
+    let fd = syscall!(
+        OPEN,
+        path.as_ptr(),
+        O_WRONLY | O_CREAT | O_TRUNC | O_APPEND | O_CLOEXEC,
+        0o644
+    );
+    if syscall::is_err(fd) {
+        return File::Off;
+    }
+    let mut header = [0_u8; 16];
+    header[..8].copy_from_slice(&MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_ne_bytes());
+    header[12..].copy_from_slice(&(mem::size_of::<Event>() as u32).to_ne_bytes());
+    if RawFd(fd).write_bytes(&header).is_err() {
+        syscall!(CLOSE, fd);
+        return File::Off;
+    }
+    File::Open(fd)
+}
+
+/// Open the trace file and make sure every ring is flushed when its thread
+/// finishes.
+extern "C" fn init() {
+    if file() == File::Off {
+        return;
+    }
+    unsafe {
+        let mut key = 0;
+        if libc::pthread_key_create(&mut key, Some(thread_exit)) == 0 {
+            THREAD_EXIT = Some(key);
+        }
+        libc::atexit(at_exit);
+    }
+}
+
+extern "C" fn at_exit() {
+    flush_trace();
+}
+
+extern "C" fn thread_exit(_: *mut libc::c_void) {
+    flush_trace();
+}
+
+fn now() -> u64 {
+    let mut ts = Timespec { sec: 0, nsec: 0 };
+    unsafe { syscall!(CLOCK_GETTIME, CLOCK_MONOTONIC, &mut ts as *mut Timespec) };
+    ts.sec as u64 * 1_000_000_000 + ts.nsec as u64
+}
+
+/// Add a call to the calling thread's ring, writing the ring out if it is full.
+///
+/// # Safety
+/// Must only be called from inside the allocator.
+#[cfg(feature = "trace")]
+pub unsafe fn record(op: Op, ptr: *const u8, old: *const u8, size: usize, align: usize) {
+    if file() == File::Off {
+        return;
+    }
+    let ring = &mut RING;
+    if ring.tid == 0 {
+        ring.tid = syscall!(GETTID) as u32;
+        // Any value but null has `thread_exit` called when the thread finishes
+        if let Some(key) = THREAD_EXIT {
+            libc::pthread_setspecific(key, 1 as *const libc::c_void);
+        }
+    }
+    ring.events[ring.len] = Event {
+        time: now(),
+        tid: ring.tid,
+        op: op as u32,
+        ptr: ptr as u64,
+        old: old as u64,
+        size: size as u64,
+        align: align as u64,
+    };
+    ring.len += 1;
+    if ring.len == RING_LEN {
+        flush_trace();
+    }
+}
+
+/// Without `trace` there is nothing to record.
+///
+/// # Safety
+/// Always safe, it is unsafe to match the real one.
+#[cfg(not(feature = "trace"))]
+#[inline]
+pub unsafe fn record(_op: Op, _ptr: *const u8, _old: *const u8, _size: usize, _align: usize) {}
+
+/// Write the calling thread's buffered events to the trace file.
+///
+/// This happens on its own when the buffer fills up, when the thread finishes
+/// and at exit. Call it if the thread may still be running at exit.
+pub fn flush_trace() {
+    #[cfg(feature = "trace")]
+    unsafe {
+        let fd = match file() {
+            File::Open(fd) => fd,
+            _ => return,
+        };
+        let ring = &mut RING;
+        let bytes = slice::from_raw_parts(
+            ring.events.as_ptr() as *const u8,
+            ring.len * mem::size_of::<Event>(),
+        );
+        // One write per ring keeps chunks from different threads whole
+        let _ = RawFd(fd).write_bytes(bytes);
+        ring.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn event_layout() {
+        assert_eq!(mem::size_of::<Event>(), 48);
+        let e = Event::EMPTY;
+        let base = &e as *const Event as usize;
+        assert_eq!(&e.ptr as *const u64 as usize - base, 16);
+        assert_eq!(&e.align as *const u64 as usize - base, 40);
+    }
+}
@@ -1,4 +1,5 @@
 use core::{cmp, mem, ptr};
+use std::ffi::CStr;
 
 // The minimum alignment guaranteed by the architecture. This value is used to
 // add fast paths for low alignment values.
@@ -24,6 +25,61 @@ pub const MIN_ALIGN: usize = 8;
 )))]
 pub const MIN_ALIGN: usize = 16;
 
+/// The size of a page of memory, this is what `mmap` and `mprotect` work in.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Round `size` up to a multiple of `PAGE_SIZE`.
+#[inline]
+pub const fn page_align(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Round `addr` down to the start of its page.
+#[inline]
+pub const fn page_floor(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+/// Sets a variable and puts the old value back when dropped, even if we are
+/// unwinding.
+pub struct Restore<T: Copy> {
+    at: *mut T,
+    old: T,
+}
+
+impl<T: Copy> Restore<T> {
+    /// Set `*at` to `value` until the guard is dropped.
+    ///
+    /// # Safety
+    /// `at` must stay valid until then, the guard must be dropped on the thread
+    /// that made it if `at` is thread local.
+    pub unsafe fn set(at: *mut T, value: T) -> Self {
+        let old = ptr::replace(at, value);
+        Restore { at, old }
+    }
+}
+
+impl<T: Copy> Drop for Restore<T> {
+    fn drop(&mut self) {
+        unsafe { *self.at = self.old };
+    }
+}
+
+/// Look up the environment variable `name` which must end with a nul byte.
+///
+/// This never allocates so it is safe to call from inside the allocator.
+pub fn env(name: &[u8]) -> Option<&'static [u8]> {
+    debug_assert_eq!(name.last(), Some(&0), "env var name must be nul terminated");
+    unsafe {
+        let val = libc::getenv(name.as_ptr().cast());
+        if val.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(val).to_bytes())
+        }
+    }
+}
+
 #[inline]
 pub fn extra_brk(size: usize) -> usize {
     // TODO: Tweak this.
@@ -40,7 +96,8 @@ pub fn extra_brk(size: usize) -> usize {
     cmp::max(MIN_EXTRA, cmp::min(MULTIPLIER * size, MAX_EXTRA))
 }
 
-/// TODO this algo aligns to 32 bit ptr sizes; use `size_of::<usize>()` somehow to fix.
+/// Round `size` up to a multiple of the word size so every `Block` is aligned.
 pub const fn align(size: usize) -> isize {
-    ((((size - 1) >> 2) << 2) + 4) as isize
+    let word = mem::size_of::<usize>();
+    ((size + word - 1) & !(word - 1)) as isize
 }
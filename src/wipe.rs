@@ -0,0 +1,78 @@
+//! Secrets should not outlive their allocation 🧽
+//!
+//! When wiping is on every allocation is zeroed as it is freed, this includes
+//! the old allocation `realloc` moves away from and memory given back to the
+//! kernel. `set_wipe_on_free` turns it on for the whole process, a `Heap` of
+//! your own can have it on by itself with `Heap::set_wipe_on_free`.
+
+use core::{
+    mem, ptr,
+    sync::atomic::{compiler_fence, AtomicBool, Ordering},
+};
+
+static WIPE: AtomicBool = AtomicBool::new(cfg!(feature = "wipe"));
+
+/// Turn wiping freed memory on or off for the whole process.
+///
+/// This starts on if the `wipe` feature is enabled.
+pub fn set_wipe_on_free(on: bool) {
+    WIPE.store(on, Ordering::Relaxed);
+}
+
+/// Is freed memory being wiped.
+#[inline]
+pub fn enabled() -> bool {
+    WIPE.load(Ordering::Relaxed)
+}
+
+/// Zero `len` bytes at `ptr` in a way the compiler is not allowed to remove.
+///
+/// # Safety
+/// `ptr` must be valid for `len` bytes.
+pub unsafe fn wipe(ptr: *mut u8, len: usize) {
+    let word = mem::size_of::<usize>();
+    let mut i = 0;
+    while i < len && (ptr as usize + i) % word != 0 {
+        ptr::write_volatile(ptr.add(i), 0);
+        i += 1;
+    }
+    while i + word <= len {
+        ptr::write_volatile(ptr.add(i).cast::<usize>(), 0);
+        i += word;
+    }
+    while i < len {
+        ptr::write_volatile(ptr.add(i), 0);
+        i += 1;
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wipe_unaligned() {
+        let mut buf = [0xAA_u8; 64];
+        unsafe { wipe(buf.as_mut_ptr().add(3), 50) };
+        assert!(buf[..3].iter().all(|b| *b == 0xAA));
+        assert!(buf[3..53].iter().all(|b| *b == 0));
+        assert!(buf[53..].iter().all(|b| *b == 0xAA));
+    }
+
+    #[test]
+    fn heap_wipes() {
+        let mut heap = crate::heap::Heap::new(crate::sim::SimBreak::new(4096));
+        heap.set_wipe_on_free(true);
+        unsafe {
+            let a = heap.malloc_block(64);
+            let b = heap.malloc_block(64);
+            ptr::write_bytes(a, 0xAA, 64);
+            heap.free(a);
+            // Poisoning writes over the zeros
+            let wiped = if crate::poison::ENABLED { crate::poison::POISON_BYTE } else { 0 };
+            assert!((0..64).all(|i| *a.add(i) == wiped));
+            heap.free(b);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Tests that need ralloc as the global allocator.
+//!
+//! ralloc is not thread safe yet and the test harness runs tests on threads of
+//! its own, so this has no harness and runs every test one after the other on
+//! the main thread.
+
+#![feature(allocator_api)]
+
+use std::{
+    alloc::{AllocRef, GlobalAlloc, Layout},
+    env, panic,
+};
+
+use ralloc::Ralloc;
+
+#[global_allocator]
+static GLOBAL: Ralloc = Ralloc;
+
+const TESTS: &[(&str, fn())] = &[
+    ("alloc_ref_grow", alloc_ref_grow),
+    ("panic_in_assert_no_alloc", panic_in_assert_no_alloc),
+    ("alloc_in_assert_no_alloc", alloc_in_assert_no_alloc),
+    ("failed_allocs_are_not_counted", failed_allocs_are_not_counted),
+    ("check_leaks_finds_leaks", check_leaks_finds_leaks),
+    ("realloc_keeps_leak_scope", realloc_keeps_leak_scope),
+    ("tag_limit", tag_limit),
+];
+
+/// Run `f` in a forked child, returns the signal that killed it if any.
+fn in_child(f: fn()) -> Option<i32> {
+    unsafe {
+        let pid = libc::fork();
+        assert!(pid >= 0);
+        if pid == 0 {
+            f();
+            libc::_exit(0);
+        }
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        if libc::WIFSIGNALED(status) {
+            Some(libc::WTERMSIG(status))
+        } else {
+            None
+        }
+    }
+}
+
+/// Growing through `AllocRef` keeps the data, and leaves it alone on failure.
+fn alloc_ref_grow() {
+    let heap = &&Ralloc;
+    let small = Layout::from_size_align(16, 8).unwrap();
+    let big = Layout::from_size_align(4096, 8).unwrap();
+    unsafe {
+        let ptr = heap.alloc(small).unwrap().cast::<u8>();
+        ptr.as_ptr().write_bytes(7, 16);
+        let ptr = heap.grow_zeroed(ptr, small, big).unwrap().cast::<u8>();
+        let data = std::slice::from_raw_parts(ptr.as_ptr(), 4096);
+        assert!(data[..16].iter().all(|b| *b == 7));
+        assert!(data[16..].iter().all(|b| *b == 0));
+
+        // A bigger alignment moves it
+        let aligned = Layout::from_size_align(8192, 256).unwrap();
+        let ptr = heap.grow(ptr, big, aligned).unwrap().cast::<u8>();
+        assert_eq!(ptr.as_ptr() as usize % 256, 0);
+        assert_eq!(*ptr.as_ptr(), 7);
+
+        ralloc::set_heap_limit(Some(0));
+        let huge = Layout::from_size_align(1 << 20, 256).unwrap();
+        assert!(heap.grow(ptr, aligned, huge).is_err());
+        ralloc::set_heap_limit(None);
+        assert_eq!(*ptr.as_ptr().add(15), 7);
+        heap.dealloc(ptr, aligned);
+    }
+}
+
+/// A panic allocates its payload and message, unwinding out of
+/// `assert_no_alloc` must not abort.
+fn panic_in_assert_no_alloc() {
+    // Keep the default hook's message out of the test output
+    panic::set_hook(Box::new(|_| {}));
+    let res = panic::catch_unwind(|| ralloc::assert_no_alloc(|| panic!("failed {}", 42)));
+    let _ = panic::take_hook();
+    let payload = res.unwrap_err();
+    let msg = match payload.downcast_ref::<String>() {
+        Some(msg) => msg.as_str(),
+        None => payload.downcast_ref::<&str>().unwrap(),
+    };
+    assert_eq!(msg, "failed 42");
+}
+
+fn alloc_in_assert_no_alloc() {
+    let killed = in_child(|| {
+        // Don't let the child write the report over the test output
+        unsafe { libc::close(2) };
+        ralloc::assert_no_alloc(|| drop(Box::new(1)));
+    });
+    assert_eq!(killed, Some(libc::SIGABRT));
+}
+
+fn failed_allocs_are_not_counted() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let counts = ralloc::count_allocs(|| unsafe {
+        ralloc::set_heap_limit(Some(0));
+        assert!(GLOBAL.alloc(layout).is_null());
+        ralloc::set_heap_limit(None);
+        let ptr = GLOBAL.alloc(layout);
+        GLOBAL.dealloc(ptr, layout);
+    });
+    assert_eq!((counts.allocs, counts.deallocs), (1, 1));
+}
+
+/// What leaks out of `check_leaks` is reported with the size asked for, needs
+/// `leak-report`.
+fn check_leaks_finds_leaks() {
+    #[cfg(feature = "leak-report")]
+    {
+        let mut kept = Vec::with_capacity(1);
+        let leaks = ralloc::check_leaks(|| {
+            drop(vec![0_u8; 100]);
+            kept.push(Box::new([0_u8; 61]));
+        });
+        assert_eq!(leaks, ralloc::Leaks { blocks: 1, bytes: 61 });
+        drop(kept);
+    }
+}
+
+/// Growing a `Vec` made outside of `check_leaks` is not a leak, needs
+/// `leak-report`.
+fn realloc_keeps_leak_scope() {
+    #[cfg(feature = "leak-report")]
+    {
+        let mut v = Vec::with_capacity(1);
+        v.push(0);
+        let leaks = ralloc::check_leaks(|| v.extend_from_slice(&[1; 100]));
+        assert!(leaks.is_empty(), "{:?}", leaks);
+    }
+}
+
+/// Tag limits and stats hold wherever the memory comes from, run with the
+/// `guarded` feature or `RALLOC_EFENCE=1` to check the page allocators.
+fn tag_limit() {
+    const TAG: u8 = 7;
+    let live = || ralloc::tag_stats()[TAG as usize].live_bytes;
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    ralloc::set_guard_sample_rate(1);
+    ralloc::set_tag_limit(TAG, Some(100));
+    unsafe {
+        let ptr = ralloc::with_tag(TAG, || GLOBAL.alloc(layout));
+        assert!(!ptr.is_null());
+        assert!(ralloc::with_tag(TAG, || GLOBAL.alloc(layout)).is_null());
+        // Outside of the tag it keeps its tag
+        let ptr = GLOBAL.realloc(ptr, layout, 32);
+        assert_eq!(live(), 32);
+
+        // What the handler allocates goes over
+        fn handler(_: ralloc::Limit, _: usize) -> bool {
+            drop(vec![0_u8; 200]);
+            false
+        }
+        ralloc::set_limit_handler(Some(handler));
+        assert!(ralloc::with_tag(TAG, || GLOBAL.alloc(Layout::new::<[u8; 80]>())).is_null());
+        ralloc::set_limit_handler(None);
+
+        GLOBAL.dealloc(ptr, Layout::from_size_align(32, 8).unwrap());
+    }
+    assert_eq!(live(), 0);
+    ralloc::set_tag_limit(TAG, None);
+    ralloc::set_guard_sample_rate(5000);
+}
+
+fn main() {
+    // `cargo test` passes a filter and flags for the harness we don't have
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    for (name, test) in TESTS {
+        if filter.as_ref().map_or(true, |f| name.contains(f.as_str())) {
+            test();
+            println!("test {} ... ok", name);
+        }
+    }
+}
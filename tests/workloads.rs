@@ -0,0 +1,479 @@
+//! Classic allocator workloads run against ralloc and the system allocator.
+//!
+//! ```notrust
+//! cargo bench --bench workloads -- [--threads 1,4] [--sizes mixed] [--ops 100000] [WORKLOAD...]
+//! ```
+//!
+//! Every workload runs in its own child process with either ralloc or the
+//! system allocator as the global allocator, so each run starts from a fresh
+//! heap and its peak RSS is its own. The table printed at the end has the
+//! throughput and the memory overhead, peak RSS over the peak number of bytes
+//! the workload had live.
+//!
+//! ralloc is not thread safe yet, it runs behind a spin lock here.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env,
+    ffi::CStr,
+    process::{self, Command},
+    sync::{
+        atomic::{self, AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::Instant,
+};
+
+use test::black_box;
+
+use ralloc::Ralloc;
+
+/// Which allocator the child uses, the parent always uses the system allocator.
+const ALLOCATOR_VAR: &[u8] = b"RALLOC_WORKLOAD_ALLOCATOR\0";
+
+const WORKLOADS: &[(&str, fn(&Params) -> usize)] = &[
+    ("larson", larson),
+    ("threadtest", threadtest),
+    ("cache-scratch", cache_scratch),
+    ("producer-consumer", producer_consumer),
+    ("churn", churn),
+    ("realloc-growth", realloc_growth),
+];
+
+#[global_allocator]
+static GLOBAL: Switch = Switch {
+    choice: AtomicU8::new(UNKNOWN),
+    lock: AtomicBool::new(false),
+    live: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+};
+
+const UNKNOWN: u8 = 0;
+const SYSTEM: u8 = 1;
+const RALLOC: u8 = 2;
+
+/// Sends every call to the allocator picked by `RALLOC_WORKLOAD_ALLOCATOR` and
+/// keeps track of the live bytes.
+struct Switch {
+    choice: AtomicU8,
+    lock: AtomicBool,
+    live: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Switch {
+    fn choice(&self) -> u8 {
+        match self.choice.load(Ordering::Relaxed) {
+            UNKNOWN => {
+                // Nothing can allocate here, look at the environment through libc
+                let var = unsafe { libc::getenv(ALLOCATOR_VAR.as_ptr().cast()) };
+                let is_ralloc = !var.is_null()
+                    && unsafe { CStr::from_ptr(var) }.to_bytes() == b"ralloc";
+                let choice = if is_ralloc {
+                    // ralloc and malloc share the program break, get malloc's arena
+                    // set up first so malloc inside libc does not move it later
+                    unsafe { libc::free(libc::malloc(1)) };
+                    RALLOC
+                } else {
+                    SYSTEM
+                };
+                self.choice.store(choice, Ordering::Relaxed);
+                choice
+            }
+            choice => choice,
+        }
+    }
+
+    fn locked<T>(&self, f: impl FnOnce() -> T) -> T {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            atomic::spin_loop_hint();
+        }
+        let ret = f();
+        self.lock.store(false, Ordering::Release);
+        ret
+    }
+
+    fn grew(&self, bytes: usize) {
+        let live = self.live.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(live, Ordering::Relaxed);
+    }
+
+    fn shrank(&self, bytes: usize) {
+        self.live.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Switch {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = match self.choice() {
+            RALLOC => self.locked(|| Ralloc.alloc(layout)),
+            _ => System.alloc(layout),
+        };
+        self.grew(layout.size());
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = match self.choice() {
+            RALLOC => self.locked(|| Ralloc.alloc_zeroed(layout)),
+            _ => System.alloc_zeroed(layout),
+        };
+        self.grew(layout.size());
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = match self.choice() {
+            RALLOC => self.locked(|| Ralloc.realloc(ptr, layout, new_size)),
+            _ => System.realloc(ptr, layout, new_size),
+        };
+        self.shrank(layout.size());
+        self.grew(new_size);
+        new
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match self.choice() {
+            RALLOC => self.locked(|| Ralloc.dealloc(ptr, layout)),
+            _ => System.dealloc(ptr, layout),
+        }
+        self.shrank(layout.size());
+    }
+}
+
+/// The sizes a workload asks for.
+#[derive(Clone, Copy, Debug)]
+enum Sizes {
+    /// 8 to 64 bytes.
+    Small,
+    /// 64 bytes to 1 KiB.
+    Medium,
+    /// 1 KiB to 64 KiB.
+    Large,
+    /// Mostly small with some medium and the odd large one.
+    Mixed,
+}
+
+impl Sizes {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "small" => Sizes::Small,
+            "medium" => Sizes::Medium,
+            "large" => Sizes::Large,
+            "mixed" => Sizes::Mixed,
+            _ => return None,
+        })
+    }
+
+    fn pick(self, rng: &mut Rng) -> usize {
+        let range = |rng: &mut Rng, lo: usize, hi: usize| lo + rng.below(hi - lo + 1);
+        match self {
+            Sizes::Small => range(rng, 8, 64),
+            Sizes::Medium => range(rng, 64, 1024),
+            Sizes::Large => range(rng, 1024, 64 * 1024),
+            Sizes::Mixed => match rng.below(100) {
+                0 => range(rng, 1024, 64 * 1024),
+                1..=9 => range(rng, 64, 1024),
+                _ => range(rng, 8, 64),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Params {
+    threads: usize,
+    sizes: Sizes,
+    /// Allocations each thread makes.
+    ops: usize,
+}
+
+/// xorshift64, good enough to pick sizes.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: usize) -> Self {
+        Rng((seed as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// A buffer of `size` bytes that has been written to so its pages are real.
+fn buffer(size: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(size);
+    buf.push(size as u8);
+    buf
+}
+
+/// Run `f` on `threads` threads and add up the operations they report.
+fn on_threads<F>(threads: usize, f: F) -> usize
+where
+    F: Fn(usize) -> usize + Copy + Send + 'static,
+{
+    let handles: Vec<_> = (0..threads).map(|t| thread::spawn(move || f(t))).collect();
+    handles.into_iter().map(|h| h.join().unwrap()).sum()
+}
+
+/// Larson: each thread frees and replaces random objects in its own array, then
+/// hands the array to a new thread which frees what the old one allocated.
+fn larson(p: &Params) -> usize {
+    const SLOTS: usize = 1000;
+    const ROUNDS: usize = 4;
+    let p = *p;
+    let mut arrays: Vec<Vec<Vec<u8>>> = (0..p.threads)
+        .map(|t| {
+            let mut rng = Rng::new(t);
+            (0..SLOTS).map(|_| buffer(p.sizes.pick(&mut rng))).collect()
+        })
+        .collect();
+    let mut ops = 0;
+    for round in 0..ROUNDS {
+        // The next round's threads inherit the arrays shifted by one
+        arrays.rotate_left(1);
+        let handles: Vec<_> = arrays
+            .drain(..)
+            .enumerate()
+            .map(|(t, mut slots)| {
+                thread::spawn(move || {
+                    let mut rng = Rng::new(round * p.threads + t + 1);
+                    for _ in 0..p.ops / ROUNDS {
+                        let i = rng.below(SLOTS);
+                        slots[i] = buffer(p.sizes.pick(&mut rng));
+                    }
+                    slots
+                })
+            })
+            .collect();
+        arrays = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        ops += p.threads * (p.ops / ROUNDS);
+    }
+    ops
+}
+
+/// Threadtest: every thread allocates a batch of objects then frees them all.
+fn threadtest(p: &Params) -> usize {
+    const BATCH: usize = 1000;
+    let p = *p;
+    on_threads(p.threads, move |t| {
+        let mut rng = Rng::new(t);
+        let mut batch = Vec::with_capacity(BATCH);
+        for _ in 0..p.ops / BATCH {
+            batch.extend((0..BATCH).map(|_| buffer(p.sizes.pick(&mut rng))));
+            batch.clear();
+        }
+        p.ops / BATCH * BATCH
+    })
+}
+
+/// Cache-scratch: every thread frees an object allocated by the main thread then
+/// allocates, writes and frees small objects which may land in the same cache line
+/// as another thread's.
+fn cache_scratch(p: &Params) -> usize {
+    let ops = p.ops;
+    let handles: Vec<_> = (0..p.threads)
+        .map(|_| Box::new([0_u8; 8]))
+        .map(|first| {
+            thread::spawn(move || {
+                drop(first);
+                for i in 0..ops {
+                    let mut obj = Box::new([0_u8; 8]);
+                    for b in obj.iter_mut() {
+                        *b = i as u8;
+                    }
+                    black_box(&obj);
+                }
+                ops
+            })
+        })
+        .collect();
+    handles.into_iter().map(|h| h.join().unwrap()).sum()
+}
+
+/// Producer-consumer: half the threads allocate and send, the other half free what
+/// they receive so every free is on a different thread from its allocation.
+fn producer_consumer(p: &Params) -> usize {
+    let p = *p;
+    let pairs = (p.threads / 2).max(1);
+    let mut handles = Vec::new();
+    for t in 0..pairs {
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(256);
+        handles.push(thread::spawn(move || {
+            let mut rng = Rng::new(t);
+            for _ in 0..p.ops {
+                tx.send(buffer(p.sizes.pick(&mut rng))).unwrap();
+            }
+            p.ops
+        }));
+        handles.push(thread::spawn(move || {
+            for buf in rx {
+                drop(buf);
+            }
+            0
+        }));
+    }
+    handles.into_iter().map(|h| h.join().unwrap()).sum()
+}
+
+/// Random-size churn: keep a working set and replace random members of it.
+fn churn(p: &Params) -> usize {
+    const LIVE: usize = 4096;
+    let p = *p;
+    on_threads(p.threads, move |t| {
+        let mut rng = Rng::new(t);
+        let mut live: Vec<Vec<u8>> = Vec::with_capacity(LIVE);
+        for _ in 0..p.ops {
+            if live.len() < LIVE && rng.below(2) == 0 {
+                live.push(buffer(p.sizes.pick(&mut rng)));
+            } else if !live.is_empty() {
+                let i = rng.below(live.len());
+                live.swap_remove(i);
+            }
+        }
+        p.ops
+    })
+}
+
+/// Realloc growth: grow buffers a byte at a time like a `Vec` being pushed to.
+fn realloc_growth(p: &Params) -> usize {
+    let p = *p;
+    on_threads(p.threads, move |t| {
+        let mut rng = Rng::new(t);
+        let mut pushes = 0;
+        while pushes < p.ops {
+            let target = p.sizes.pick(&mut rng) * 16;
+            let mut buf = Vec::new();
+            for i in 0..target {
+                buf.push(i as u8);
+            }
+            pushes += target;
+            black_box(&buf);
+        }
+        pushes
+    })
+}
+
+/// The most memory this process has had resident in KiB.
+fn peak_rss() -> usize {
+    unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage.ru_maxrss as usize
+    }
+}
+
+/// Run one workload in this process and print `ops nanos peak_live peak_rss`.
+fn child(name: &str, p: &Params) {
+    let (_, run) = WORKLOADS.iter().find(|(n, _)| *n == name).unwrap();
+    let start = Instant::now();
+    let ops = run(p);
+    let nanos = start.elapsed().as_nanos();
+    println!(
+        "{} {} {} {}",
+        ops,
+        nanos,
+        GLOBAL.peak.load(Ordering::Relaxed),
+        peak_rss()
+    );
+}
+
+fn usage() -> ! {
+    eprintln!("usage: workloads [--threads 1,4] [--sizes small|medium|large|mixed] [--ops N] [WORKLOAD...]");
+    eprintln!("workloads:");
+    for (name, _) in WORKLOADS {
+        eprintln!("    {}", name);
+    }
+    process::exit(2)
+}
+
+fn main() {
+    let mut threads = vec![1, 4];
+    let mut params = Params {
+        threads: 1,
+        sizes: Sizes::Mixed,
+        ops: 100_000,
+    };
+    let mut names = Vec::new();
+    let mut run = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            // Passed by `cargo bench`
+            "--bench" => {}
+            "--threads" => {
+                threads = value()
+                    .split(',')
+                    .map(|n| n.parse().unwrap_or_else(|_| usage()))
+                    .collect()
+            }
+            "--sizes" => params.sizes = Sizes::parse(&value()).unwrap_or_else(|| usage()),
+            "--ops" => params.ops = value().parse().unwrap_or_else(|_| usage()),
+            "--run" => run = Some(value()),
+            "--help" | "-h" => usage(),
+            name if WORKLOADS.iter().any(|(n, _)| *n == name) => names.push(name.to_string()),
+            _ => usage(),
+        }
+    }
+
+    if let Some(name) = run {
+        params.threads = threads[0];
+        return child(&name, &params);
+    }
+    if names.is_empty() {
+        names = WORKLOADS.iter().map(|(n, _)| n.to_string()).collect();
+    }
+
+    let exe = env::current_exe().unwrap();
+    println!(
+        "{:<18} {:<8} {:>7} {:>10} {:>12} {:>12} {:>9}",
+        "workload", "alloc", "threads", "Mops/s", "peak live", "peak rss", "overhead"
+    );
+    for name in &names {
+        for &t in &threads {
+            for alloc in &["system", "ralloc"] {
+                let out = Command::new(&exe)
+                    .env("RALLOC_WORKLOAD_ALLOCATOR", alloc)
+                    .args(&["--run", name, "--threads", &t.to_string()])
+                    .args(&["--ops", &params.ops.to_string()])
+                    .args(&["--sizes", &format!("{:?}", params.sizes).to_lowercase()])
+                    .output()
+                    .unwrap();
+                if !out.status.success() {
+                    println!("{:<18} {:<8} {:>7} failed: {}", name, alloc, t, out.status);
+                    continue;
+                }
+                let nums: Vec<f64> = String::from_utf8_lossy(&out.stdout)
+                    .split_whitespace()
+                    .map(|n| n.parse().unwrap())
+                    .collect();
+                let (ops, nanos, live, rss) = (nums[0], nums[1], nums[2], nums[3] * 1024.0);
+                println!(
+                    "{:<18} {:<8} {:>7} {:>10.2} {:>10}Ki {:>10}Ki {:>8.2}x",
+                    name,
+                    alloc,
+                    t,
+                    ops * 1000.0 / nanos,
+                    (live / 1024.0) as usize,
+                    (rss / 1024.0) as usize,
+                    rss / live.max(1.0)
+                );
+            }
+        }
+    }
+}